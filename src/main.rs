@@ -1,3 +1,3 @@
 mod server;
 
-fn main() { server::run(); }
\ No newline at end of file
+fn main() { std::process::exit(server::run()); }
@@ -1,36 +1,68 @@
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::num::IntErrorKind;
 use std::fs::File;
-use std::net::TcpListener;
-use std::time::Duration;
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, SystemTime};
 use std::{fs, io, thread};
 use std::io::{prelude::*, ErrorKind};
-use std::net::TcpStream;
-use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 mod threadpool;
 mod error;
+mod config;
+mod path;
+mod escape;
+mod response;
+mod connection;
+mod date;
+mod range;
+mod mime;
+mod cache;
+mod listing;
+mod template;
+mod assets;
+mod actions;
+mod trash;
+mod upload;
+mod multipart;
+mod unique;
 
 use threadpool::ThreadPool;
 use error::ServerError;
-
-const CR                  : u8      = 13;
-const LF                  : u8      = 10;
-const HYPHEN              : u8      = 45;
-const BUFFER_SIZE         : usize   = 8096;
-const STREAM_BLOCK_IN_SECS: u64     = 5;
-const CRLF                : [u8; 2] = [CR,LF];
-const DASH                : [u8; 1] = [HYPHEN];
-const HEADER_END          : [u8; 4] = [CR,LF,CR,LF];
-const MAX_RETRIES         : u8      = 5;
-
-enum HTTPRequestType { GET, POST }
+use config::Config;
+use response::{Body, Generator, Response, Status};
+use connection::{Connection, Framing, RequestBody};
+use range::Ranges;
+use cache::{Fingerprint, Precondition, Validators};
+use listing::{Listing, Order, SortKey};
+use upload::{ConflictPolicy, Stored};
+use multipart::Multipart;
+
+const CR                : u8       = 13;
+const LF                : u8       = 10;
+const CRLF              : [u8; 2]  = [CR,LF];
+const HEADER_END        : [u8; 4]  = [CR,LF,CR,LF];
+const ALLOWED_METHODS   : &str     = "GET, POST, PUT, DELETE, MOVE, COPY";
+const MAX_HEADER_SIZE   : usize    = 64 * 1024;
+const LINGER_TIMEOUT    : Duration = Duration::from_secs(2);
+const FILE_CACHE_CONTROL: &str     = "no-cache";
+/// Text fields of upload forms are read into memory, so they are kept small.
+const MAX_FIELD_SIZE    : usize    = 64 * 1024;
+
+#[allow(clippy::upper_case_acronyms)]
+enum HTTPRequestType { GET, POST, PUT, DELETE, MOVE, COPY }
 
 impl Display for HTTPRequestType {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      HTTPRequestType::GET  => write!(f, "GET"),
-      HTTPRequestType::POST => write!(f, "POST")
+      HTTPRequestType::GET    => write!(f, "GET"),
+      HTTPRequestType::POST   => write!(f, "POST"),
+      HTTPRequestType::PUT    => write!(f, "PUT"),
+      HTTPRequestType::DELETE => write!(f, "DELETE"),
+      HTTPRequestType::MOVE   => write!(f, "MOVE"),
+      HTTPRequestType::COPY   => write!(f, "COPY")
     }
   }
 }
@@ -40,9 +72,13 @@ impl TryFrom<&str> for HTTPRequestType {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
       match value {
-        "GET"  => Ok(HTTPRequestType::GET),
-        "POST" => Ok(HTTPRequestType::POST),
-        _      => Err(ServerError::HTTPParseError(format!("HTTP Parse Error: Cannot convert {value} to HTTPRequestType")))
+        "GET"    => Ok(HTTPRequestType::GET),
+        "POST"   => Ok(HTTPRequestType::POST),
+        "PUT"    => Ok(HTTPRequestType::PUT),
+        "DELETE" => Ok(HTTPRequestType::DELETE),
+        "MOVE"   => Ok(HTTPRequestType::MOVE),
+        "COPY"   => Ok(HTTPRequestType::COPY),
+        _        => Err(ServerError::MethodNotAllowed(format!("Method {value} is not supported")))
       }
     }
 }
@@ -57,6 +93,68 @@ struct Request {
 }
 
 impl Request {
+  fn header(&self, name: &str) -> Option<&String> {
+    self.info.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+  }
+
+  /// The request target without query and fragment, still percent-encoded.
+  fn path(&self) -> &str {
+    self.url.split(['?', '#']).next().unwrap_or_default()
+  }
+
+  /// The percent-decoded value of a query parameter.
+  fn query(&self, name: &str) -> Option<String> {
+    self.url
+      .split_once('?')
+      .map(|(_, query)| query.split('#').next().unwrap_or_default())?
+      .split('&')
+      .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+      .find(|(key, _)| *key == name)
+      .map(|(_, value)| path::decode_url(&value.replace('+', " ")).to_string_lossy().to_string())
+  }
+
+  /// Whether a directory listing should be sent as JSON: requested by `?format=json` or by an `Accept` header
+  /// that asks for JSON but not for HTML.
+  fn wants_json(&self) -> bool {
+    match self.query("format").as_deref() {
+      Some("json") => true,
+      Some("html") => false,
+      _            => self.header("Accept").is_some_and(|accept| accept.contains("application/json") && !accept.contains("text/html"))
+    }
+  }
+
+  /// How the body of the request is delimited. A `Transfer-Encoding` other than `chunked` can't be decoded, and one
+  /// combined with `Content-Length` is refused, as the two could be read differently by a proxy in front.
+  fn framing(&self) -> Result<Framing, ServerError> {
+    let length = self.header("Content-Length");
+    match self.header("Transfer-Encoding") {
+      None                                                   =>
+        Ok(Framing::Length(length.map(|value| parse_content_length(value)).transpose()?.unwrap_or(0) as u64)),
+      Some(_) if length.is_some()                            =>
+        Err(ServerError::BadRequest("Request has both Transfer-Encoding and Content-Length".to_string())),
+      Some(coding) if coding.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+      Some(coding)                                           =>
+        Err(ServerError::BadRequest(format!("Transfer-Encoding `{coding}` is not supported")))
+    }
+  }
+
+  /// Whether the client waits for `100 Continue` before sending the body. HTTP/1.0 clients never do.
+  fn expects_continue(&self) -> bool {
+    self.version != "HTTP/1.0" && self.header("Expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+  }
+
+  /// Whether the client wants the connection kept open: HTTP/1.1 does unless told otherwise, HTTP/1.0 only if asked.
+  fn keep_alive(&self) -> bool {
+    let tokens = self.header("Connection").map(|c| c.to_ascii_lowercase()).unwrap_or_default();
+    if tokens.split(',').any(|t| t.trim() == "close") {
+      false
+    } else if tokens.split(',').any(|t| t.trim() == "keep-alive") {
+      true
+    } else {
+      self.version == "HTTP/1.1"
+    }
+  }
+
   fn compile_header_info(lines: Vec<&str>, skip: usize) -> HashMap<String, String> {
     lines
     .iter()
@@ -83,6 +181,11 @@ impl Request {
     let header = lines[0].split(' ').collect::<Vec<&str>>();
 
     if header.len() != 3 { return Err(ServerError::HTTPParseError(format!("HTTP Parse Error: Malformed header ({})", lines[0]))) }
+    // Header names are kept as spelled, so repeating one in another case would make `framing` pick either of them
+    for name in ["Content-Length", "Transfer-Encoding"] {
+      let repeated = lines.iter().skip(1).filter(|line| line.split_once(':').is_some_and(|(key, _)| key.trim().eq_ignore_ascii_case(name))).count() > 1;
+      if repeated { return Err(ServerError::BadRequest(format!("Request has more than one {name} header"))) }
+    }
 
     Ok(Self {
       r_type : HTTPRequestType::try_from(header[0])?,
@@ -99,329 +202,266 @@ impl Display for Request {
   }
 }
 
-fn compile_response(status_line: &str, contents: Vec<u8>) -> Vec<u8> {
-  [status_line.as_bytes(),
-   "\r\nContent-Length:".as_bytes(),
-   contents.len().to_string().as_bytes(),
-   "\r\n\r\n".as_bytes(),
-   &contents].concat()
+/// Answers with `304 Not Modified` or fails with `412 Precondition Failed` if the conditional headers say so.
+fn check_preconditions(validators: &Validators, header: &Request, cache_control: &str) -> Result<Option<Response>, ServerError> {
+  match validators.evaluate(header) {
+    Precondition::Proceed     => Ok(None),
+    Precondition::NotModified => Ok(Some(validators.apply(Response::new(Status::NotModified, Vec::new())).with_header("Cache-Control", cache_control))),
+    Precondition::Failed      => Err(ServerError::PreconditionFailed(format!("Precondition failed for {}", header.url)))
+  }
+}
+
+/// The most recent modification of a directory or any of its entries, i.e. of its listing.
+fn newest_modified(path: &Path) -> Option<SystemTime> {
+  fs::read_dir(path)
+    .ok()?
+    .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+    .chain(fs::metadata(path).ok()?.modified().ok())
+    .max()
 }
 
-fn dir(relative_path: String) -> Result<Vec<u8>, ServerError>  {
-  let absolute_path = format!("files/{relative_path}");
-  let (mut dirs, mut files): (Vec<Vec<u8>>,Vec<Vec<u8>>) = fs::read_dir(&absolute_path)?.fold(
-    (Vec::new(),Vec::new()),
-    |mut acc, r_entry| {
-      match r_entry {
-        Ok(entry) => {
-          match entry.metadata() {
-            Ok(md) if md.is_dir()  => acc.0.push(entry.file_name().as_bytes().to_vec()),
-            Ok(md) if md.is_file() => acc.1.push(entry.file_name().as_bytes().to_vec()),
-            Ok(_)                  => println!("List Dir Error: {:?} is neither file nor dir", entry.file_name()),
-            Err(e)                 => println!("List Dir Error: {}", e.to_string())
-          } acc
-        },
-        Err(e)    => { println!("List Dir Error: {}", e.to_string()); acc }
-      }
+fn serve_file(config: &Config, target: &Path, header: &Request, cache_control: &str) -> Result<Response, ServerError> {
+  let mut file = File::open(target)?;
+  let metadata = file.metadata()?;
+  if metadata.is_dir() { return Err(io::Error::from(ErrorKind::IsADirectory).into()) }
+
+  let validators = Validators::for_file(&mut file, &metadata, config.etag)?;
+  if let Some(response) = check_preconditions(&validators, header, cache_control)? { return Ok(response) }
+
+  let length        = metadata.len();
+  let content_type  = match mime::from_path(target, &config.mime_types) {
+    Some(mime_type) => mime_type.to_string(),
+    None            => {
+      let mut start = Vec::with_capacity(mime::SNIFF_LENGTH);
+      (&mut file).take(mime::SNIFF_LENGTH as u64).read_to_end(&mut start)?;
+      mime::sniff(&start).to_string()
     }
-  );
-
-  dirs.sort_by(|a, b| a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()));
-  dirs = dirs.into_iter().map(|s| ["<button class=\"btnLink invisible\" >💾</button> <a href=\"".as_bytes().to_vec(),s.clone(),"/\"><button class=\"btnLink\">".as_bytes().to_vec(),s,"/</button></a>".as_bytes().to_vec()].concat()).collect();
-  files.sort_by(|a, b| a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()));
-  files = files.into_iter().map(|s| ["<button class=\"btnLink\" onclick=\"javascript:download('".as_bytes().to_vec(),s.clone(),"', true)\" >💾</button> <button class=\"btnLink\" onclick=\"javascript:download('".as_bytes().to_vec(),s.clone(),"', false)\" onmouseenter=\"javascript:show_preview('".as_bytes().to_vec(),s.clone(),"');\" onmousedown=\"javascript:show_preview('".as_bytes().to_vec(),s.clone(),"');\"') onmouseleave=\"javascript:hide_preview();\" onmouseout=\"javascript:hide_preview();\" onmouseup=\"javascript:hide_preview();\">".as_bytes().to_vec(),s,"</button>".as_bytes().to_vec()].concat() ).collect();
-  dirs.append(files.as_mut());
-  Ok(dirs.into_iter().fold(Vec::new(), |mut acc: Vec<u8>, mut entry| { acc.append(&mut entry); acc.append("<br>".as_bytes().to_vec().as_mut()); acc }))
+  };
+  let content_type  = mime::with_charset(&content_type);
+
+  let ranges = match (header.header("Range"), header.header("If-Range")) {
+    (Some(_), Some(if_range)) if !range::if_range_matches(if_range, &validators) => Ranges::Full,
+    (Some(range), _)                                                            => range::parse(range, length),
+    (None, _)                                                                   => Ranges::Full
+  };
+
+  let response = match ranges {
+    Ranges::Full => {
+      Response::ok(Body::File { file, offset: 0, length })
+        .with_header("Content-Type", &content_type)
+    },
+    Ranges::Partial(ranges) if ranges.len() == 1 => {
+      Response::new(Status::PartialContent, Body::File { file, offset: ranges[0].start, length: ranges[0].len() })
+        .with_header("Content-Type", &content_type)
+        .with_header("Content-Range", &ranges[0].content_range(length))
+    },
+    Ranges::Partial(ranges) => {
+      let boundary = range::boundary();
+      Response::new(Status::PartialContent, range::multipart(file, ranges, length, &content_type, &boundary))
+        .with_header("Content-Type", &format!("multipart/byteranges; boundary={boundary}"))
+    },
+    Ranges::Unsatisfiable => {
+      Response::new(Status::RangeNotSatisfiable, Vec::new())
+        .with_header("Content-Range", &format!("bytes */{length}"))
+    }
+  };
+
+  Ok(validators.apply(response)
+    .with_header("Accept-Ranges", "bytes")
+    .with_header("Cache-Control", cache_control))
 }
 
-fn read_until_done<F>(stream: &mut TcpStream, mut f: F) -> Result<(), ServerError>
-where F: FnMut(usize, &mut bool, &mut Vec<u8>) {
-  let mut buffer = [0; BUFFER_SIZE];
-  let mut done = false;
-  let mut retries = 0;
-  let mut cumulative_buffer: Vec<u8> = Vec::new();
-  while !done {
-    if retries > MAX_RETRIES { return Err(ServerError::TransportError(io::Error::from_raw_os_error(22))); }
-    match stream.read(&mut buffer) {
-      Ok(read) => {
-        if read == 0 { thread::sleep(Duration::from_secs(1)); retries += 1 } else {
-          retries = 0;
-          cumulative_buffer.append(&mut buffer[0..read].to_vec());
-          f(read, &mut done, &mut cumulative_buffer);
-        }
+/// Receives the files of a `multipart/form-data` body into `directory`. Text fields are read, but ignored.
+fn upload_files<R: Read>(stream: &mut R, config: &Config, header: &Request, directory: PathBuf, boundary: &str) -> Result<Vec<Stored>, ServerError> {
+  let policy = ConflictPolicy::for_request(config, header)?;
+  let mut form = Multipart::new(&mut *stream, boundary, config.buffer_size);
+  let mut stored = Vec::new();
+  let mut parts = 0;
+
+  while let Some(part) = form.next_part()? {
+    parts += 1;
+    if parts > config.max_upload_parts {
+      return Err(ServerError::PayloadTooLarge(format!("Upload has more than {} parts", config.max_upload_parts)))
+    }
+    match part.filename {
+      Some(file_name) if !file_name.is_empty() => {
+        let mut file = upload::begin(config, header, &directory, &file_name, policy)?;
+        form.read_body(&mut file)?;
+        stored.push(file.commit()?);
       },
-      Err(e)   => {
-        retries += 1;
-        match e.kind() {
-          ErrorKind::WouldBlock => { thread::sleep(Duration::from_secs(STREAM_BLOCK_IN_SECS)); },
-          _ => { thread::sleep(Duration::from_secs(1)); println!("Header Read Error: {e}") }
-        }
-      }
+      // Browsers send a file input left empty as a file without name and content
+      Some(_) => { form.read_body(&mut io::sink())?; },
+      None    => println!("Ignoring form field {} ({} bytes)", part.name, form.read_text(MAX_FIELD_SIZE)?.len())
     }
   }
-  Ok(())
+
+  // Skip the epilogue, so the connection is ready for the next request
+  io::copy(stream, &mut io::sink())?;
+  Ok(stored)
 }
 
-fn upload_files(    stream       : &mut TcpStream,
-                mut body_vec     : Vec<u8>,
-                path             : String,
-                content_separator: String,
-                content_length   : usize) -> Result<(), ServerError> {
-
-  fn compile_content_disposition(header_vec: Vec<u8>) -> Result<HashMap<String, String>, ServerError> {
-    let mut content_disposition = HashMap::new();
-    let content_header_info = Request::compile_header_info(String::from_utf8_lossy(header_vec.as_slice()).split("\r\n").collect::<Vec<&str>>(), 0);
-    if let Some(content_disposition_string) = content_header_info.get("Content-Disposition") {
-      content_disposition_string
-      .split("; ")
-      .filter_map(|s| s.split_once("="))
-      .for_each(|(key,value)| { content_disposition.insert(key.to_string(), value.trim_matches('"').to_string()); });
-
-      if !content_disposition.contains_key("filename") {
-        return Err(ServerError::HTTPParseError("Content Incomplete; no filename found".to_string()));
-      }
-    } else {
-      return Err(ServerError::HTTPParseError("Content Incomplete; no Content-Disposition found".to_string()));
-    }
-    Ok(content_disposition)
-  }
+/// Reads the next request head from the connection. Returns `Ok(None)` once the client is done.
+fn read_request(connection: &mut Connection, config: &Config) -> Result<Option<Request>, ServerError> {
+  let header_vec = match connection.read_head(Duration::from_secs(config.keep_alive_timeout), MAX_HEADER_SIZE)? {
+    Some(header_vec) => header_vec,
+    None             => return Ok(None)
+  };
 
-  fn get_content_disposition(body_vec: &mut Vec<u8>, stream: &mut TcpStream, total_read: &mut usize, content_length: usize) -> Result<HashMap<String, String>, ServerError> {
-    let mut header_vec = Vec::new();
-    if let Some(cutoff) = body_vec.windows(4).position(|w| w.cmp(&HEADER_END).is_eq()) {
-        header_vec = body_vec[..cutoff].to_vec();
-        *body_vec = body_vec[cutoff+HEADER_END.len()..].to_vec();
-    } else { // Otherwise, read until header is ready
-      header_vec.append(body_vec);
-      read_until_done(stream, |read: usize, done: &mut bool, cumulative_buffer: &mut Vec<u8>| {
-        *total_read += read;
-        if let Some(cutoff) = cumulative_buffer.windows(4).position(|w| w.cmp(&HEADER_END).is_eq()) {
-          header_vec.append(&mut cumulative_buffer[..cutoff].to_vec());
-          *body_vec = cumulative_buffer[cutoff+HEADER_END.len()..].to_vec();
-          *done = true;
-        }
-      })?;
-    }
-    println!("### BEGIN CONTENT HEADER (Read {total_read}/{content_length}) ###");
-    println!("{}{}", String::from_utf8_lossy(&header_vec), String::from_utf8_lossy(&HEADER_END));
-    println!("### END CONTENT HEADER ###");
-    Ok(compile_content_disposition(header_vec)?)
-  }
+  let header_string = String::from_utf8_lossy(&header_vec).to_string();
+  println!("### BEGIN HEADER ###");
+  println!("{header_string}");
+  println!("### END HEADER ###");
 
-  fn create_file(path: &String, content_disposition: HashMap<String, String>) -> Result<File, ServerError>{
-    match content_disposition.get("filename") {
-      Some(file_name) =>
-        if file_name.is_empty() {
-          Err(ServerError::HTTPParseError("Uploading File Failed: No file name found".to_string()))
-        } else {
-          Ok(File::create(["files/",path.as_str(),file_name].concat())?)
-        },
-      None => Err(ServerError::HTTPParseError("Uploading File Failed: No file name found".to_string()))
-    }
-  }
+  // Parse Header (Tokenize: "<GET|...> <URL> <HTTP/\d+.\d+>\n(<field>:<value>\n)+")
+  Ok(Some(Request::parse_header(header_string)?))
+}
 
-  let first_separator = &[&DASH, content_separator.as_bytes(), &CRLF].concat();
-  let mid_separator = &[&[CR], &[LF], &DASH, &DASH, content_separator.as_bytes()].concat();
-  let mut total_read = body_vec.len()+1;
+fn parse_content_length(value: &str) -> Result<usize, ServerError> {
+  value.parse::<usize>().map_err(|e| match e.kind() {
+    IntErrorKind::PosOverflow => ServerError::PayloadTooLarge(format!("Content-Length {value} is too large")),
+    _                         => ServerError::from(e)
+  })
+}
 
-  if content_length <= first_separator.len() {
-    return Ok(());
+fn handle<R: Read>(stream: &mut R, config: &Config, header: &Request) -> Result<Response, ServerError> {
+  if let Some(expect) = header.header("Expect").filter(|expect| !expect.eq_ignore_ascii_case("100-continue")) {
+    return Err(ServerError::ExpectationFailed(format!("Expectation `{expect}` is not supported")))
   }
-
-  // If the body is too short, read more
-  if body_vec.len() < first_separator.len() {
-    read_until_done(stream, |read: usize, done: &mut bool, cumulative_buffer: &mut Vec<u8>| {
-      total_read += read;
-      if body_vec.len() + cumulative_buffer.len() >= first_separator.len() {
-        body_vec.append(cumulative_buffer);
-        *done = true;
-      }
-    })?;
+  if matches!(header.r_type, HTTPRequestType::GET) && header.url.starts_with(assets::ROUTE) {
+    return assets::serve(config, header)
   }
-
-  // If the body does not start with the first seperator, the body is malformed
-  if !body_vec.starts_with(&first_separator) {
-    return Err(ServerError::HTTPParseError("Content malformed; first separator not found".to_string()))
+  if header.path().starts_with(trash::PREFIX) {
+    return trash::handle(config, header)
   }
-
-  // Remove first separator from body_vec
-  // println!("{}", String::from_utf8_lossy(&first_separator));
-  body_vec = body_vec.split_at(first_separator.len()).1.to_vec();
-
-  let mut content_complete = false;
-  let mut part_complete = false;
-
-  let mut content_disposition = get_content_disposition(&mut body_vec, stream, &mut total_read, content_length)?;
-  let mut file = create_file(&path, content_disposition)?;
-
-  // While content is not complete
-  while !content_complete {
-    //  We may still find a last_separator in the body_vec
-    let mut pos = 0;
-    while !part_complete
-       && !content_complete
-       && pos+mid_separator.len()+2 <= body_vec.len() {
-      if body_vec[pos] == CR {
-        // println!("### A BEGIN CONTENT BODY (Read {total_read}/{content_length} Write {}/{}) ###", body_vec[..pos].len(), body_vec.len());
-        // println!("{}", String::from_utf8_lossy(&body_vec[..pos]));
-        file.write_all(&body_vec[..pos])?;
-        body_vec = body_vec[pos..].to_vec();
-        if body_vec.starts_with(&mid_separator) {
-          body_vec = body_vec[mid_separator.len()+2..].to_vec();
-          content_complete = total_read == content_length;
-          part_complete = true;
-          //println!("### CONTENT READ ###");
-          pos = 0;
-        } else {
-          pos = 1;
+  match header.r_type {
+    HTTPRequestType::GET => {
+      let target = path::resolve_url(&config.root, &header.url, config.symlinks)?;
+      if header.path().ends_with('/') {
+        if !target.is_dir() { return Err(ServerError::NotFound(format!("{} is not a directory", header.url))) }
+        let mut listing = Listing::read(&target, &config.mime_types)?;
+        let (sort, order) = (SortKey::parse(header.query("sort").as_deref()), Order::parse(header.query("order").as_deref()));
+        listing.sort(sort, order);
+        if target == config.root { listing.entries.retain(|entry| entry.name != trash::TRASH_DIR) }
+        upload::hide_temporary(&mut listing);
+        if header.wants_json() {
+          // Streamed in chunks, after a first pass that only computes the entity tag
+          let url_path = path::decode_url(header.path()).to_string_lossy().to_string();
+          let mut fingerprint = Fingerprint::default();
+          listing.write_json(&url_path, &mut fingerprint)?;
+          let validators = Validators::for_fingerprint(&fingerprint.finish(), newest_modified(&target));
+          if let Some(response) = check_preconditions(&validators, header, FILE_CACHE_CONTROL)? { return Ok(response) }
+          let generate: Generator = Box::new(move |out| listing.write_json(&url_path, out));
+          return Ok(validators.apply(Response::ok(Body::Generator { length: None, generate }))
+            .with_header("Content-Type", "application/json")
+            .with_header("Vary", "Accept")
+            .with_header("Cache-Control", FILE_CACHE_CONTROL))
         }
-        //println!("### END CONTENT BODY ###");
+        let mut context = listing.context(header.path(), target == config.root, sort, order);
+        context.insert("trash", config.trash.into());
+        let page = template::render(config, "files.html", &context)?;
+        let validators = Validators::for_generated(page.as_bytes(), newest_modified(&target));
+        if let Some(response) = check_preconditions(&validators, header, FILE_CACHE_CONTROL)? { return Ok(response) }
+        Ok(validators.apply(Response::ok(page.into_bytes()))
+          .with_header("Content-Type", "text/html; charset=utf-8")
+          .with_header("Vary", "Accept")
+          .with_header("Cache-Control", FILE_CACHE_CONTROL))
+      } else if target.is_dir() {
+        // Links in the listing are relative, so it is only served below the trailing slash
+        let location = path::encode_url_path(&path::url_path(&header.url)?)?;
+        let location = format!("{location}/{}", &header.url[header.path().len()..]);
+        Ok(Response::text(Status::MovedPermanently, &format!("Moved to {location}")).with_header("Location", &location))
       } else {
-        pos += 1;
+        serve_file(config, &target, header, FILE_CACHE_CONTROL)
       }
-    }
-
-    if pos > 0 {
-      // println!("### B BEGIN CONTENT BODY (Read {total_read}/{content_length} Write {}/{}) ###", body_vec[..pos].len(), body_vec.len());
-      // println!("{}", String::from_utf8_lossy(&body_vec[..pos]));
-      // println!("### END CONTENT BODY ###");
-      file.write_all(&body_vec[..pos])?;
-      body_vec = body_vec[pos..].to_vec();
-    }
-
-    if !part_complete && total_read < content_length  {
-      read_until_done(stream, |read: usize, done: &mut bool, cumulative_buffer: &mut Vec<u8>| {
-        total_read += read;
-        body_vec.append(cumulative_buffer);
-        *done = body_vec.len() >= mid_separator.len();
-      })?;
-    } else if total_read < content_length {
-      content_disposition = get_content_disposition(&mut body_vec, stream, &mut total_read, content_length)?;
-      file = create_file(&path, content_disposition)?;
-      part_complete = false;
-    }
-  }
-  Ok(())
-}
-
-fn decode_url_char(s: &str) -> Result<char, ServerError> {
-  Ok(char::from(u8::from_str_radix(s.replace("%", "").as_str(),16)?))
-}
-
-fn decode_url(s: &str) -> String {
-  let encoded_path = s.chars().collect::<Vec<char>>();
-  let mut decoded_path = String::new();
-  let mut i: usize = 0;
-
-  while i < encoded_path.len() {
-    if encoded_path[i] == '%' && i+2 < encoded_path.len() {
-      match decode_url_char(encoded_path[i..=i+2].into_iter().collect::<String>().as_str()) {
-        Ok(c)  => { decoded_path.push(c); i += 3; },
-        Err(e) => { println!("Error decoding URL: {e}"); i += 1; }
+    },
+    HTTPRequestType::POST => {
+      if let Some(action) = header.header("Action") {
+        match action.as_str() {
+          "create_directory" => actions::create_directory(config, header),
+          "delete"           => actions::delete(config, header),
+          "move"             => actions::move_entry(config, header),
+          "copy"             => actions::copy_entry(config, header),
+          _ => Err(ServerError::BadRequest(format!("Invalid Action `{action}`")))
+        }
+      } else if let Some(content_type) = header.header("Content-Type") {
+        let (directory, boundary) = (path::url_path(&header.url)?, multipart::boundary(content_type)?);
+        // Before anything is read, so a client waiting for `100 Continue` does not send the body at all
+        upload::check_request(config, header, &directory)?;
+        let stored = upload_files(stream, config, header, directory, &boundary)?;
+        let files = stored.iter().map(Stored::to_json).collect::<Vec<String>>();
+        Ok(Response::new(Status::Ok, format!("{{\"files\":[{}]}}", files.join(",")).into_bytes())
+          .with_header("Content-Type", "application/json"))
+      } else {
+        Err(ServerError::BadRequest("POST is neither an Action nor has a Content-Type".to_string()))
       }
-    } else {
-      decoded_path.push(encoded_path[i]);
-      i += 1;
-    }
+    },
+    HTTPRequestType::PUT    => upload::put(stream, config, header),
+    HTTPRequestType::DELETE => actions::delete(config, header),
+    HTTPRequestType::MOVE   => actions::move_entry(config, header),
+    HTTPRequestType::COPY   => actions::copy_entry(config, header)
   }
-
-  decoded_path
 }
 
-fn serve(mut stream: TcpStream) -> Result<(), ServerError> {
-  let mut header_vec: Vec<u8> = Vec::new();
-  let mut body_vec: Vec<u8> = Vec::new();
-
-  // Get Request
-  read_until_done(&mut stream, |read: usize, done: &mut bool, cumulative_buffer: &mut Vec<u8>| {
-    if cumulative_buffer.len() >= HEADER_END.len() {
-      let mut cutoff = HEADER_END.len();
-      for window in cumulative_buffer.windows(HEADER_END.len()) {
-        if window.cmp(&HEADER_END).is_eq() {
-          *done = true;
-          if cutoff < read { body_vec = cumulative_buffer[cutoff+1..].to_vec(); }
-          break;
-        } else {
-          header_vec.push(window[0]);
-          cutoff+=1;
-        }
-      }
-      if !*done { cumulative_buffer.clear() }
-    }
-  })?;
+fn serve(stream: TcpStream, config: &Config) -> Result<(), ServerError> {
+  let mut connection = Connection::new(stream, config.buffer_size);
 
-  let header_string = String::from_utf8_lossy(&header_vec).to_string();
-  println!("### BEGIN HEADER ###");
-  println!("{header_string}");
-  println!("### END HEADER ###");
-
-  // Parse Header (Tokenize: "<GET|...> <URL> <HTTP/\d+.\d+>\n(<field>:<value>\n)+")
-  let header = Request::parse_header(header_string)?;
-
-  let ok        = "HTTP/1.1 200 OK";
-  let not_found = "HTTP/1.1 404 NOT FOUND";
-
-  // Evaluate header
-  let (status_line, contents) =
-    if header.url.contains("..") {
-      println!("Server Error: Indirection in path forbidden");
-      (not_found, "Woops".as_bytes().to_vec())
-    } else {
-      let path = decode_url(header.url.chars().skip(1).collect::<String>().as_str());
-      match header.r_type {
-        HTTPRequestType::GET => {
-          if header.url.ends_with("/") {
-            (ok, fs::read_to_string("files.html")?.replace("{{Entries}}", String::from_utf8(dir(path)?)?.as_str()).as_bytes().to_vec())
-          } else if path.starts_with("static/icons") {
-            (ok, fs::read(path)?)
-          } else {
-            (ok, fs::read(format!("files/{path}"))?)
-          }
-        },
-        HTTPRequestType::POST => {
-          if let Some(action) = header.info.get("Action") {
-            match action.as_str() {
-              "create_directory" => {
-                if header.url.is_empty() {
-                  (ok, "Can't create directory without name...".as_bytes().to_vec())
-                } else {
-                  let decoded_path = decode_url(["files",header.url.as_str()].concat().as_str());
-                  fs::create_dir(&decoded_path)?;
-                  (ok, ["Directory ", decoded_path.as_str(), " created..."].concat().as_bytes().to_vec())
-                }
-              },
-              _ => {
-                println!("Server Error: Invalid Action `{action}`");
-                (not_found, "Woops".as_bytes().to_vec())
-              }
-            }
-          } else if let (Some(content_separator), Some(content_length)) = (
-                header.info.get("Content-Type").and_then(|content_type| content_type.split_once("boundary=").map(|(_,sep)| sep.to_string())),
-                header.info.get("Content-Length")) {
-            match upload_files(&mut stream, body_vec, decode_url(path.as_str()), content_separator, content_length.parse::<usize>()?) {
-              Ok(()) => { (ok, ":)".as_bytes().to_vec()) },
-              Err(e) => { println!("Server Error: File upload failed. {e}"); (not_found, "Woops".as_bytes().to_vec()) }
-            }
-          } else {
-            println!("Server Error: POST is neither an Action nor has a Content-Type");
-            (not_found, "Woops".as_bytes().to_vec())
-          }
-        }
+  for served in 1..=config.max_requests {
+    // Get Request
+    let request = read_request(&mut connection, config).and_then(|request| match request {
+      Some(header) => {
+        let framing = header.framing()?;
+        Ok(Some((header, framing)))
+      },
+      None         => Ok(None)
+    });
+    let (header, framing) = match request {
+      Ok(Some(request)) => request,
+      Ok(None)          => break,
+      Err(e)            => {
+        // The client may already be gone, so failing to answer is not an error on its own
+        let _ = Response::error(&e, None).with_header("Connection", "close").write_to(connection.stream(), false);
+        return Err(e)
       }
     };
 
-  // Respond
-  stream.write_all(compile_response(status_line, contents).as_slice())?;
-  stream.flush()?;
+    // Evaluate header
+    let mut body = RequestBody::new(&mut connection, framing, header.expects_continue(), config.max_upload_size);
+    let response = handle(&mut body, config, &header).unwrap_or_else(|e| {
+      println!("Server Error: {e}");
+      Response::error(&e, header.header("Accept"))
+    });
+
+    // A body that was not read completely would be mistaken for the next request, and a response of unknown
+    // length is either sent in chunks or, to HTTP/1.0 clients, terminated by closing the connection
+    let chunked = response.body.len().is_none() && header.version != "HTTP/1.0";
+    let unread = !body.finished();
+    let keep_alive = header.keep_alive() && !unread && (response.body.len().is_some() || chunked) && served < config.max_requests;
+    let response = match (keep_alive, header.version.as_str()) {
+      (false, _)          => response.with_header("Connection", "close"),
+      (true, "HTTP/1.0")  => response
+        .with_header("Connection", "keep-alive")
+        .with_header("Keep-Alive", &format!("timeout={}, max={}", config.keep_alive_timeout, config.max_requests-served)),
+      (true, _)           => response
+    };
+
+    // Respond
+    response.write_to(connection.stream(), chunked)?;
+
+    if unread { connection.linger(LINGER_TIMEOUT) }
+    if !keep_alive { break }
+  }
 
   // Done
   Ok(())
 }
 
-fn listen(listener: TcpListener, pool: ThreadPool) {
+fn listen(listener: TcpListener, pool: ThreadPool, config: Arc<Config>) {
   for stream in listener.incoming() {
     match stream {
-      Ok(stream)  => pool.execute(move || if let Err(e) = serve(stream) { println!("Request failed: {e}") }),
+      Ok(stream)  => {
+        let config = Arc::clone(&config);
+        pool.execute(move || if let Err(e) = serve(stream, &config) { println!("Request failed: {e}") })
+      },
       Err(e)      => println!("{e}")
     }
   }
@@ -430,9 +470,15 @@ fn listen(listener: TcpListener, pool: ThreadPool) {
 pub fn run() -> i32 {
   let mut rc = 0;
 
-  if let Err(e) = fs::read_dir("files/") {
+  let mut config = match Config::load(std::env::args().skip(1)) {
+    Ok(Some(config)) => config,
+    Ok(None)         => return rc,
+    Err(e)           => { println!("Initialize Server Error: {e}"); return 5 }
+  };
+
+  if let Err(e) = fs::read_dir(&config.root) {
     if e.kind() == io::ErrorKind::NotFound {
-      if let Err(e) = fs::create_dir("files/") {
+      if let Err(e) = fs::create_dir_all(&config.root) {
         println!("Initialize Server Error: {e}"); rc = 1;
       }
     } else if e.kind() != io::ErrorKind::AlreadyExists {
@@ -440,11 +486,28 @@ pub fn run() -> i32 {
     }
   }
 
+  // Resolved paths are compared against the root, so it has to be canonical
   if rc == 0 {
-    match TcpListener::bind("192.168.178.43:8000") {
+    match fs::canonicalize(&config.root) {
+      Ok(root) => config.root = root,
+      Err(e)   => { println!("Initialize Server Error: {e}"); rc = 2; }
+    }
+  }
+
+  if rc == 0 {
+    match TcpListener::bind(config.bind_address()) {
       Ok(listener) => {
-        match ThreadPool::new(16) {
-          Ok(pool) => listen(listener, pool),
+        match ThreadPool::new(config.workers) {
+          Ok(pool) => {
+            println!("Serving {} on http://{}", config.root.display(), config.bind_address());
+            upload::clean_up(&config);
+            let config = Arc::new(config);
+            if config.trash && config.trash_retention > 0 {
+              let config = Arc::clone(&config);
+              thread::spawn(move || loop { trash::expire(&config); thread::sleep(trash::EXPIRY_INTERVAL) });
+            }
+            listen(listener, pool, config)
+          },
           Err(e)   => { println!("Create Thread Pool Error: {e}"); rc = 3; }
         }
       },
@@ -453,4 +516,4 @@ pub fn run() -> i32 {
     println!("Shutting down...OK");
   }
   rc
-}
\ No newline at end of file
+}
@@ -0,0 +1,242 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use super::cache;
+use super::config::Config;
+use super::error::ServerError;
+use super::escape;
+use super::path;
+use super::response::{Response, Status};
+use super::trash;
+use super::upload;
+use super::Request;
+
+/// A yes/no option of a request, given either as header (`Overwrite: F`) or in the query (`?overwrite=false`).
+fn flag(header: &Request, name: &str, default: bool) -> bool {
+  match header.header(name).cloned().or_else(|| header.query(&name.to_ascii_lowercase())).map(|value| value.to_ascii_lowercase()).as_deref() {
+    Some("t" | "true" | "1" | "yes") => true,
+    Some("f" | "false" | "0" | "no") => false,
+    _                                => default
+  }
+}
+
+/// The path of `target` as clients see it, for messages.
+fn display(config: &Config, target: &Path) -> String {
+  target.strip_prefix(&config.root).unwrap_or(target).to_string_lossy().to_string()
+}
+
+/// The metadata of `target` itself (not following symlinks), failing with `404 Not Found` if it does not exist.
+fn existing(config: &Config, target: &Path) -> Result<fs::Metadata, ServerError> {
+  fs::symlink_metadata(target).map_err(|e| match e.kind() {
+    ErrorKind::NotFound => ServerError::NotFound(format!("{} does not exist", display(config, target))),
+    _                   => e.into()
+  })
+}
+
+/// The entry named by the `Destination` header, which holds an absolute URL or path as in WebDAV.
+fn destination(config: &Config, header: &Request) -> Result<PathBuf, ServerError> {
+  let value = header.header("Destination").ok_or_else(|| ServerError::BadRequest("Destination header is missing".to_string()))?;
+  let url = match value.split_once("://") {
+    Some((_, rest)) => rest.find('/').map(|start| &rest[start..]).unwrap_or("/"),
+    None            => value.as_str()
+  };
+  if !url.starts_with('/') { return Err(ServerError::BadRequest(format!("Destination {value} is not an absolute path"))) }
+  path::resolve_entry_url(&config.root, url, config.symlinks)
+}
+
+/// Makes way for replacing `destination`, unless the `Overwrite` flag is false. Like `delete`, the old entry goes
+/// to the recycle bin if the trash is enabled and the `Permanent` flag is not given. Returns whether there was one.
+fn clear_destination(config: &Config, header: &Request, destination: &Path) -> Result<bool, ServerError> {
+  let existing = match fs::symlink_metadata(destination) {
+    Ok(_) if !flag(header, "Overwrite", true) =>
+      return Err(ServerError::PreconditionFailed(format!("{} already exists", display(config, destination)))),
+    Ok(existing)                              => existing,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+    Err(e)                                    => return Err(e.into())
+  };
+  if config.trash && !flag(header, "Permanent", false) {
+    trash::put(config, destination)?;
+  } else if existing.is_dir() {
+    fs::remove_dir_all(destination)?;
+  } else {
+    fs::remove_file(destination)?;
+  }
+  Ok(true)
+}
+
+/// Refuses to act on the recycle bin, which is only changed through its own handler.
+fn check_outside_trash(config: &Config, target: &Path) -> Result<(), ServerError> {
+  match trash::contains(config, target) {
+    true  => Err(ServerError::Forbidden(format!("{} can only be changed through {}", display(config, target), trash::PREFIX))),
+    false => Ok(())
+  }
+}
+
+/// Checks the source and destination of a move or copy, returning the metadata of the source.
+fn check_transfer(config: &Config, source: &Path, destination: &Path, verb: &str) -> Result<fs::Metadata, ServerError> {
+  if source == config.root || destination == config.root {
+    return Err(ServerError::Forbidden(format!("The root directory can't be {verb} or replaced")))
+  }
+  check_outside_trash(config, source)?;
+  check_outside_trash(config, destination)?;
+  upload::check_reserved(destination.file_name().unwrap_or_default())?;
+  let metadata = existing(config, source)?;
+  if source == destination {
+    return Err(ServerError::Forbidden(format!("{} can't be {verb} onto itself", display(config, source))))
+  }
+  if metadata.is_dir() && destination.starts_with(source) {
+    return Err(ServerError::BadRequest(format!("{} can't be {verb} into itself", display(config, source))))
+  }
+  if !destination.parent().is_some_and(Path::is_dir) {
+    return Err(ServerError::Conflict(format!("The parent of {} does not exist", display(config, destination))))
+  }
+  Ok(metadata)
+}
+
+/// Creates a directory, and with the `Parents` flag also its missing parents (like `mkdir -p`).
+/// Answers `201 Created`, or `200 OK` if the directory already exists and `Parents` is given; otherwise an existing
+/// entry is a `409 Conflict`. Names of the directories to create are checked against the name policy.
+pub fn create_directory(config: &Config, header: &Request) -> Result<Response, ServerError> {
+  let target = path::resolve_url(&config.root, &header.url, config.symlinks)?;
+  if target == config.root { return Err(ServerError::BadRequest("Can't create directory without name...".to_string())) }
+  let parents = flag(header, "Parents", false);
+
+  match fs::metadata(&target) {
+    Ok(metadata) if metadata.is_dir() && parents =>
+      return Ok(Response::text(Status::Ok, &format!("Directory {} already exists", display(config, &target)))),
+    Ok(_)                                        => return Err(ServerError::Conflict(format!("{} already exists", display(config, &target)))),
+    Err(e) if e.kind() == ErrorKind::NotFound    => (),
+    Err(e)                                       => return Err(e.into())
+  }
+
+  let missing = target.ancestors().take_while(|ancestor| fs::symlink_metadata(ancestor).is_err()).collect::<Vec<&Path>>();
+  for directory in &missing {
+    let name = directory.file_name().unwrap_or_default();
+    path::check_name(name, config.name_policy)?;
+    upload::check_reserved(name)?;
+  }
+  if parents {
+    fs::create_dir_all(&target)?;
+  } else if missing.len() > 1 {
+    return Err(ServerError::Conflict(format!("The parent of {} does not exist", display(config, &target))))
+  } else {
+    fs::create_dir(&target)?;
+  }
+
+  println!("Created directory {}", target.display());
+  Ok(Response::text(Status::Created, &format!("Directory {} created", display(config, &target))))
+}
+
+/// Removes a file or symlink, or a directory. Non-empty directories are only removed with the `Recursive` flag.
+/// With the trash enabled, entries go to the recycle bin unless the `Permanent` flag is given.
+pub fn delete(config: &Config, header: &Request) -> Result<Response, ServerError> {
+  let target = path::resolve_entry_url(&config.root, &header.url, config.symlinks)?;
+  if target == config.root { return Err(ServerError::Forbidden("The root directory can't be deleted".to_string())) }
+  check_outside_trash(config, &target)?;
+
+  let metadata = existing(config, &target)?;
+  let not_empty = || ServerError::Conflict(format!("Directory {} is not empty, delete it recursively", display(config, &target)));
+  if metadata.is_file() { cache::check_write(header, &target, config.etag)? }
+
+  if config.trash && !flag(header, "Permanent", false) {
+    if metadata.is_dir() && !flag(header, "Recursive", false) && fs::read_dir(&target)?.next().is_some() { return Err(not_empty()) }
+    trash::put(config, &target)?;
+    return Ok(Response::text(Status::Ok, &format!("{} moved to the trash", display(config, &target))))
+  }
+
+  if metadata.is_dir() {
+    let removed = if flag(header, "Recursive", false) { fs::remove_dir_all(&target) } else { fs::remove_dir(&target) };
+    match removed {
+      Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty => return Err(not_empty()),
+      removed                                            => removed?
+    }
+  } else {
+    fs::remove_file(&target)?;
+  }
+
+  println!("Deleted {}", target.display());
+  Ok(Response::text(Status::Ok, &format!("{} deleted", display(config, &target))))
+}
+
+/// Moves or renames an entry to the `Destination` header. An existing destination is replaced unless `Overwrite`
+/// is false, in which case the move fails with `412 Precondition Failed`; what it held goes to the trash if enabled.
+pub fn move_entry(config: &Config, header: &Request) -> Result<Response, ServerError> {
+  let source      = path::resolve_entry_url(&config.root, &header.url, config.symlinks)?;
+  let destination = destination(config, header)?;
+  let metadata    = check_transfer(config, &source, &destination, "moved")?;
+  if metadata.is_file() { cache::check_write(header, &source, config.etag)? }
+
+  let replaced = clear_destination(config, header, &destination)?;
+  fs::rename(&source, &destination)?;
+
+  println!("Moved {} to {}", source.display(), destination.display());
+  let status = if replaced { Status::Ok } else { Status::Created };
+  Ok(Response::text(status, &format!("{} moved to {}", display(config, &source), display(config, &destination))))
+}
+
+/// Copies an entry to the `Destination` header without the bytes passing through the client. Directories are
+/// copied recursively; mtimes and permissions are kept where possible. `Overwrite` works as for `move_entry`.
+/// If some entries could not be copied, the response is a `207 Multi-Status` listing them.
+pub fn copy_entry(config: &Config, header: &Request) -> Result<Response, ServerError> {
+  let source      = path::resolve_entry_url(&config.root, &header.url, config.symlinks)?;
+  let destination = destination(config, header)?;
+  check_transfer(config, &source, &destination, "copied")?;
+  let replaced = clear_destination(config, header, &destination)?;
+
+  let mut failures = Vec::new();
+  let copied = copy_tree(&source, &destination, &mut failures);
+  println!("Copied {} to {} ({copied} entries, {} failed)", source.display(), destination.display(), failures.len());
+
+  if failures.is_empty() {
+    let status = if replaced { Status::Ok } else { Status::Created };
+    Ok(Response::text(status, &format!("{} copied to {}", display(config, &source), display(config, &destination))))
+  } else {
+    let failed = failures.iter()
+      .map(|(path, e)| format!("{{\"path\":\"{}\",\"error\":\"{}\"}}", escape::json(&display(config, path)), escape::json(&e.to_string())))
+      .collect::<Vec<String>>();
+    Ok(Response::new(Status::MultiStatus, format!("{{\"copied\":{copied},\"failed\":[{}]}}", failed.join(",")).into_bytes())
+      .with_header("Content-Type", "application/json"))
+  }
+}
+
+/// Copies `source` to `destination`, collecting the entries that failed instead of stopping at the first one.
+/// Returns the number of entries copied.
+fn copy_tree(source: &Path, destination: &Path, failures: &mut Vec<(PathBuf, io::Error)>) -> usize {
+  let copied = fs::symlink_metadata(source).and_then(|metadata| {
+    if metadata.file_type().is_symlink() {
+      symlink(fs::read_link(source)?, destination)?;
+      return Ok(1)
+    }
+    if metadata.is_file() {
+      // Uses copy_file_range, which reflinks on filesystems supporting it, and copies the permissions
+      fs::copy(source, destination)?;
+      preserve_modified(destination, &metadata);
+      return Ok(1)
+    }
+    if !metadata.is_dir() { return Err(io::Error::new(ErrorKind::Unsupported, "Not a file, directory or symlink")) }
+
+    fs::create_dir(destination)?;
+    let mut copied = 1;
+    for entry in fs::read_dir(source)? {
+      match entry {
+        Ok(entry) => copied += copy_tree(&entry.path(), &destination.join(entry.file_name()), failures),
+        Err(e)    => failures.push((source.to_path_buf(), e))
+      }
+    }
+    // Only now, so a read-only directory can still be filled and its mtime isn't bumped by the entries
+    fs::set_permissions(destination, metadata.permissions())?;
+    preserve_modified(destination, &metadata);
+    Ok(copied)
+  });
+
+  copied.unwrap_or_else(|e| { failures.push((source.to_path_buf(), e)); 0 })
+}
+
+/// Best effort: a copy with a fresh mtime is still a good copy.
+fn preserve_modified(destination: &Path, metadata: &fs::Metadata) {
+  if let Ok(modified) = metadata.modified() {
+    let _ = File::open(destination).and_then(|file| file.set_modified(modified));
+  }
+}
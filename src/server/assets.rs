@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use super::cache::{self, Validators};
+use super::config::Config;
+use super::error::ServerError;
+use super::path::{self, SymlinkPolicy};
+use super::response::Response;
+use super::{mime, Request};
+
+const PREFIX: &str = "/static/";
+/// Requests below this are answered with assets. The rest of `/static/` is left to the files in the root, which may
+/// well have a `static` directory of their own.
+pub const ROUTE: &str = "/static/icons/";
+
+/// For URLs carrying the content hash, which change whenever the asset does.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+/// For plain URLs, e.g. the ones referenced from `site.webmanifest`.
+const ASSET_CACHE_CONTROL    : &str = "public, max-age=86400";
+/// For URLs with an outdated hash, served anyway so pages cached by a browser still render.
+const STALE_CACHE_CONTROL    : &str = "no-cache";
+
+/// `static/` compiled into the binary, keyed by the path below `/static/`.
+const EMBEDDED: &[(&str, &[u8])] = &[
+  ("icons/android-chrome-192x192.png", include_bytes!("../../static/icons/android-chrome-192x192.png")),
+  ("icons/android-chrome-384x384.png", include_bytes!("../../static/icons/android-chrome-384x384.png")),
+  ("icons/android-chrome-512x512.png", include_bytes!("../../static/icons/android-chrome-512x512.png")),
+  ("icons/apple-touch-icon.png"      , include_bytes!("../../static/icons/apple-touch-icon.png")),
+  ("icons/browserconfig.xml"         , include_bytes!("../../static/icons/browserconfig.xml")),
+  ("icons/favicon-16x16.png"         , include_bytes!("../../static/icons/favicon-16x16.png")),
+  ("icons/favicon-32x32.png"         , include_bytes!("../../static/icons/favicon-32x32.png")),
+  ("icons/favicon.ico"               , include_bytes!("../../static/icons/favicon.ico")),
+  ("icons/mstile-150x150.png"        , include_bytes!("../../static/icons/mstile-150x150.png")),
+  ("icons/safari-pinned-tab.svg"     , include_bytes!("../../static/icons/safari-pinned-tab.svg")),
+  ("icons/sharingduckdb.jpg"         , include_bytes!("../../static/icons/sharingduckdb.jpg")),
+  ("icons/site.webmanifest"          , include_bytes!("../../static/icons/site.webmanifest"))
+];
+
+/// The current contents of asset `name`: from `static_dir` if one is configured and has it, embedded otherwise.
+fn load(config: &Config, name: &str) -> Result<Option<Cow<'static, [u8]>>, ServerError> {
+  if let Some(static_dir) = &config.static_dir {
+    match fs::read(path::resolve(static_dir, Path::new(name), SymlinkPolicy::FollowInsideRoot)?) {
+      Ok(contents)                              => return Ok(Some(Cow::Owned(contents))),
+      Err(e) if e.kind() == ErrorKind::NotFound => (),
+      Err(e)                                    => return Err(e.into())
+    }
+  }
+  Ok(EMBEDDED.iter().find(|(embedded, _)| *embedded == name).map(|(_, contents)| Cow::Borrowed(*contents)))
+}
+
+/// Splits `icons/favicon.0123456789abcdef.ico` into `icons/favicon.ico` and the hash.
+fn split_hash(name: &str) -> (String, Option<&str>) {
+  let file_start = name.rfind('/').map(|i| i + 1).unwrap_or(0);
+  let mut parts = name[file_start..].rsplitn(3, '.');
+  match (parts.next(), parts.next(), parts.next()) {
+    (Some(extension), Some(hash), Some(stem)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) =>
+      (format!("{}{stem}.{extension}", &name[..file_start]), Some(hash)),
+    _ => (name.to_string(), None)
+  }
+}
+
+/// The content-hashed URL of asset `name`, e.g. `/static/icons/favicon.0123456789abcdef.ico`.
+/// Unknown assets get their plain URL.
+pub fn url(config: &Config, name: &str) -> Result<String, ServerError> {
+  let name = name.trim_start_matches('/');
+  Ok(match (load(config, name)?, name.rsplit_once('.')) {
+    (Some(contents), Some((stem, extension))) if !extension.contains('/') => format!("{PREFIX}{stem}.{}.{extension}", cache::fingerprint(&contents)),
+    _                                                                     => format!("{PREFIX}{name}")
+  })
+}
+
+/// Serves `/static/<name>`, with or without the content hash.
+pub fn serve(config: &Config, header: &Request) -> Result<Response, ServerError> {
+  let not_found = || ServerError::NotFound(format!("Asset {} not found", header.url));
+  let requested = path::url_path(&header.url)?;
+  let requested = requested.to_str().and_then(|url| url.strip_prefix(PREFIX)).ok_or_else(not_found)?;
+  let (name, hash) = split_hash(requested);
+  let (name, hash, contents) = match (load(config, &name)?, hash) {
+    (Some(contents), _) => (name, hash, contents),
+    // A name that merely looks hashed
+    (None, Some(_))     => (requested.to_string(), None, load(config, requested)?.ok_or_else(not_found)?),
+    (None, None)        => return Err(not_found())
+  };
+
+  let cache_control = match hash {
+    Some(hash) if hash.eq_ignore_ascii_case(&cache::fingerprint(&contents)) => IMMUTABLE_CACHE_CONTROL,
+    Some(_)                                                                => STALE_CACHE_CONTROL,
+    None                                                                   => ASSET_CACHE_CONTROL
+  };
+  let validators = Validators::for_generated(&contents, None);
+  if let Some(response) = super::check_preconditions(&validators, header, cache_control)? { return Ok(response) }
+
+  let content_type = mime::from_path(Path::new(&name), &config.mime_types).map(str::to_string).unwrap_or_else(|| mime::sniff(&contents).to_string());
+  Ok(validators.apply(Response::ok(contents.into_owned()))
+    .with_header("Content-Type", &mime::with_charset(&content_type))
+    .with_header("Cache-Control", cache_control))
+}
@@ -0,0 +1,274 @@
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::date;
+use super::error::ServerError;
+use super::response::Response;
+use super::{HTTPRequestType, Request};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME : u64 = 0x100000001b3;
+
+/// How file entity tags are computed: from inode, size and mtime, or from the contents.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EtagMode { Metadata, Hash }
+
+impl TryFrom<&str> for EtagMode {
+  type Error = ServerError;
+
+  fn try_from(value: &str) -> Result<Self, Self::Error> {
+    match value {
+      "metadata" => Ok(EtagMode::Metadata),
+      "hash"     => Ok(EtagMode::Hash),
+      _          => Err(ServerError::ConfigError(format!("Invalid ETag mode `{value}` (expected metadata or hash)")))
+    }
+  }
+}
+
+pub enum Precondition { Proceed, NotModified, Failed }
+
+pub struct Validators {
+  pub etag         : String,
+  pub last_modified: Option<SystemTime>
+}
+
+impl Validators {
+  /// Strong validators for a file, leaving `file` at its start.
+  pub fn for_file(file: &mut File, metadata: &Metadata, mode: EtagMode) -> io::Result<Self> {
+    let last_modified = metadata.modified().ok();
+    let etag = match mode {
+      EtagMode::Metadata => {
+        let nanos = last_modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_nanos()).unwrap_or_default();
+        format!("\"{:x}-{:x}-{nanos:x}\"", metadata.ino(), metadata.len())
+      },
+      EtagMode::Hash => {
+        let hash = hash_reader(&mut *file)?;
+        file.seek(SeekFrom::Start(0))?;
+        format!("\"{hash:016x}\"")
+      }
+    };
+    Ok(Self { etag, last_modified })
+  }
+
+  /// Weak validators for generated content, such as a directory listing.
+  pub fn for_generated(contents: &[u8], last_modified: Option<SystemTime>) -> Self {
+    Self::for_fingerprint(&fingerprint(contents), last_modified)
+  }
+
+  /// Weak validators for generated content that is streamed, given the `fingerprint` a `Fingerprint` computed of it.
+  pub fn for_fingerprint(fingerprint: &str, last_modified: Option<SystemTime>) -> Self {
+    Self { etag: format!("W/\"{fingerprint}\""), last_modified }
+  }
+
+  pub fn apply(&self, response: Response) -> Response {
+    let response = response.with_header("ETag", &self.etag);
+    match self.last_modified {
+      Some(modified) => response.with_header("Last-Modified", &date::format(modified)),
+      None           => response
+    }
+  }
+
+  /// Evaluates the conditional headers of `header` in the order of RFC 9110, 13.2.2.
+  pub fn evaluate(&self, header: &Request) -> Precondition {
+    let is_get = matches!(header.r_type, HTTPRequestType::GET);
+
+    if let Some(if_match) = header.header("If-Match") {
+      if !matches_any(if_match, &self.etag, true) { return Precondition::Failed }
+    } else if let (Some(since), Some(modified)) = (header.header("If-Unmodified-Since").and_then(|v| date::parse(v)), self.last_modified) {
+      if date::truncate(modified) > since { return Precondition::Failed }
+    }
+
+    if let Some(if_none_match) = header.header("If-None-Match") {
+      if matches_any(if_none_match, &self.etag, false) {
+        return if is_get { Precondition::NotModified } else { Precondition::Failed }
+      }
+    } else if let (true, Some(since), Some(modified)) = (is_get, header.header("If-Modified-Since").and_then(|v| date::parse(v)), self.last_modified) {
+      if date::truncate(modified) <= since { return Precondition::NotModified }
+    }
+
+    Precondition::Proceed
+  }
+}
+
+/// Checks `If-Match`, `If-None-Match` and `If-Unmodified-Since` before `path` is (over)written by an upload,
+/// so a client cannot replace changes it has not seen.
+pub fn check_write(header: &Request, path: &Path, mode: EtagMode) -> Result<(), ServerError> {
+  let failed = |reason: &str| Err(ServerError::PreconditionFailed(format!("{}: {reason}", path.file_name().unwrap_or_default().to_string_lossy())));
+
+  match File::open(path) {
+    Ok(mut file) => {
+      let metadata = file.metadata()?;
+      match Validators::for_file(&mut file, &metadata, mode)?.evaluate(header) {
+        Precondition::Proceed => Ok(()),
+        _                     => failed("the file was changed or already exists")
+      }
+    },
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+      if header.header("If-Match").is_some() { failed("the file does not exist") } else { Ok(()) }
+    },
+    Err(e) => Err(e.into())
+  }
+}
+
+/// A short hash of `contents` for entity tags and cache-busting URLs.
+pub fn fingerprint(contents: &[u8]) -> String {
+  format!("{:016x}", hash(FNV_OFFSET, contents))
+}
+
+/// Computes the `fingerprint` of everything written to it, without holding on to any of it.
+pub struct Fingerprint(u64);
+
+impl Default for Fingerprint {
+  fn default() -> Self { Self(FNV_OFFSET) }
+}
+
+impl Fingerprint {
+  pub fn finish(&self) -> String { format!("{:016x}", self.0) }
+}
+
+impl Write for Fingerprint {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0 = hash(self.0, buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// Compares an `If-Match`/`If-None-Match` list against `etag`. Weak tags never match strongly.
+fn matches_any(list: &str, etag: &str, strong: bool) -> bool {
+  let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+  list.trim() == "*"
+    || list.split(',').map(str::trim).any(|candidate| {
+      if strong { candidate == etag && !etag.starts_with("W/") } else { opaque(candidate) == opaque(etag) }
+    })
+}
+
+fn hash(mut state: u64, bytes: &[u8]) -> u64 {
+  for byte in bytes {
+    state ^= u64::from(*byte);
+    state = state.wrapping_mul(FNV_PRIME);
+  }
+  state
+}
+
+fn hash_reader<R: Read>(mut reader: R) -> io::Result<u64> {
+  let mut buffer = [0; 64 * 1024];
+  let mut state  = FNV_OFFSET;
+  loop {
+    match reader.read(&mut buffer)? {
+      0    => return Ok(state),
+      read => state = hash(state, &buffer[..read])
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::time::Duration;
+
+  const MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+  const EARLIER : &str = "Sun, 06 Nov 1994 08:49:36 GMT";
+  const LATER   : &str = "Sun, 06 Nov 1994 08:49:38 GMT";
+
+  fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+    let head = headers.iter().fold(format!("{method} /file HTTP/1.1"), |acc, (name, value)| format!("{acc}\r\n{name}: {value}"));
+    Request::parse_header(head).unwrap()
+  }
+
+  fn validators(etag: &str) -> Validators {
+    // Sub-second precision, which HTTP dates lack
+    Validators { etag: etag.to_string(), last_modified: Some(UNIX_EPOCH + Duration::from_millis(784_111_777_250)) }
+  }
+
+  fn evaluate(method: &str, headers: &[(&str, &str)]) -> &'static str {
+    match validators("\"v1\"").evaluate(&request(method, headers)) {
+      Precondition::Proceed     => "proceed",
+      Precondition::NotModified => "not modified",
+      Precondition::Failed      => "failed"
+    }
+  }
+
+  #[test]
+  fn evaluates_if_match_before_if_unmodified_since() {
+    assert_eq!(evaluate("PUT", &[]), "proceed");
+    assert_eq!(evaluate("PUT", &[("If-Match", "\"v1\"")]), "proceed");
+    assert_eq!(evaluate("PUT", &[("If-Match", "\"v0\", \"v1\"")]), "proceed");
+    assert_eq!(evaluate("PUT", &[("If-Match", "*")]), "proceed");
+    assert_eq!(evaluate("PUT", &[("If-Match", "\"v0\"")]), "failed");
+    // If-Match uses the strong comparison
+    assert_eq!(evaluate("PUT", &[("If-Match", "W/\"v1\"")]), "failed");
+    // A matching If-Match makes If-Unmodified-Since irrelevant
+    assert_eq!(evaluate("PUT", &[("If-Match", "\"v1\""), ("If-Unmodified-Since", EARLIER)]), "proceed");
+
+    assert_eq!(evaluate("PUT", &[("If-Unmodified-Since", MODIFIED)]), "proceed");
+    assert_eq!(evaluate("PUT", &[("If-Unmodified-Since", LATER)]), "proceed");
+    assert_eq!(evaluate("PUT", &[("If-Unmodified-Since", EARLIER)]), "failed");
+    assert_eq!(evaluate("PUT", &[("If-Unmodified-Since", "not a date")]), "proceed");
+  }
+
+  #[test]
+  fn evaluates_if_none_match_before_if_modified_since() {
+    assert_eq!(evaluate("GET", &[("If-None-Match", "\"v1\"")]), "not modified");
+    // If-None-Match uses the weak comparison
+    assert_eq!(evaluate("GET", &[("If-None-Match", "W/\"v1\"")]), "not modified");
+    assert_eq!(evaluate("GET", &[("If-None-Match", "\"v0\", W/\"v1\"")]), "not modified");
+    assert_eq!(evaluate("GET", &[("If-None-Match", "*")]), "not modified");
+    assert_eq!(evaluate("GET", &[("If-None-Match", "\"v0\"")]), "proceed");
+    assert_eq!(evaluate("PUT", &[("If-None-Match", "\"v1\"")]), "failed");
+    assert_eq!(evaluate("PUT", &[("If-None-Match", "*")]), "failed");
+    // A failing If-None-Match makes If-Modified-Since irrelevant
+    assert_eq!(evaluate("GET", &[("If-None-Match", "\"v0\""), ("If-Modified-Since", LATER)]), "proceed");
+
+    assert_eq!(evaluate("GET", &[("If-Modified-Since", MODIFIED)]), "not modified");
+    assert_eq!(evaluate("GET", &[("If-Modified-Since", LATER)]), "not modified");
+    assert_eq!(evaluate("GET", &[("If-Modified-Since", EARLIER)]), "proceed");
+    assert_eq!(evaluate("GET", &[("If-Modified-Since", "Thu, 01 Jan 300000000000 00:00:00 GMT")]), "proceed");
+    // Only for GET
+    assert_eq!(evaluate("PUT", &[("If-Modified-Since", LATER)]), "proceed");
+  }
+
+  #[test]
+  fn evaluates_if_match_before_if_none_match() {
+    assert_eq!(evaluate("GET", &[("If-Match", "\"v0\""), ("If-None-Match", "\"v0\"")]), "failed");
+    assert_eq!(evaluate("GET", &[("If-Match", "\"v1\""), ("If-None-Match", "\"v1\"")]), "not modified");
+  }
+
+  #[test]
+  fn never_matches_weak_tags_strongly() {
+    let request = request("PUT", &[("If-Match", "W/\"v1\"")]);
+    assert!(matches!(validators("W/\"v1\"").evaluate(&request), Precondition::Failed));
+    let request = self::request("GET", &[("If-None-Match", "\"v1\"")]);
+    assert!(matches!(validators("W/\"v1\"").evaluate(&request), Precondition::NotModified));
+  }
+
+  #[test]
+  fn checks_writes_against_the_current_file() {
+    let directory = std::env::temp_dir().join(format!("fileserve-cache-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let (existing, missing) = (directory.join("existing"), directory.join("missing"));
+    fs::write(&existing, b"contents").unwrap();
+
+    for mode in [EtagMode::Metadata, EtagMode::Hash] {
+      let mut file = File::open(&existing).unwrap();
+      let metadata = file.metadata().unwrap();
+      let etag = Validators::for_file(&mut file, &metadata, mode).unwrap().etag;
+      let check = |path: &Path, headers: &[(&str, &str)]| check_write(&request("PUT", headers), path, mode);
+      let failed = |result: Result<(), ServerError>| matches!(result, Err(ServerError::PreconditionFailed(_)));
+
+      assert!(check(&existing, &[]).is_ok());
+      assert!(check(&existing, &[("If-Match", &etag)]).is_ok());
+      assert!(failed(check(&existing, &[("If-Match", "\"stale\"")])));
+      assert!(failed(check(&existing, &[("If-None-Match", "*")])));
+      assert!(check(&missing, &[("If-None-Match", "*")]).is_ok());
+      assert!(failed(check(&missing, &[("If-Match", "*")])));
+      assert!(failed(check(&missing, &[("If-Match", &etag)])));
+    }
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use super::error::ServerError;
+use super::path::{NamePolicy, SymlinkPolicy};
+use super::cache::EtagMode;
+use super::upload::ConflictPolicy;
+
+const ENV_PREFIX    : &str = "FILESERVE_";
+const CONFIG_ENV    : &str = "FILESERVE_CONFIG";
+const DEFAULT_CONFIG: &str = "fileserve.conf";
+
+const USAGE: &str = "\
+Usage: fileserve [OPTIONS]
+
+Options:
+  -c, --config <FILE>              Read settings from FILE (default: ./fileserve.conf if present)
+  -a, --address <ADDRESS>          Address to bind to (default: 127.0.0.1)
+  -p, --port <PORT>                Port to bind to (default: 8000)
+  -r, --root <DIR>                 Directory to serve (default: files)
+      --static <DIR>               Serve assets from DIR before the built-in ones, for development
+      --theme <DIR>                Directory with templates overriding the built-in ones
+  -w, --workers <N>                Number of worker threads (default: 16)
+      --buffer-size <BYTES>        Size of the socket read buffer (default: 8096)
+      --keep-alive-timeout <SECS>  Close idle connections after SECS (default: 5)
+      --max-requests <N>           Requests served per connection before closing it (default: 100)
+      --symlinks <POLICY>          Symlinks to follow: inside_root, any or deny (default: inside_root)
+      --upload-conflict <POLICY>   When an upload clashes with a file: overwrite, reject or rename (default: rename)
+      --max-upload-size <BYTES>    Largest upload request, 0 for no limit (default: 0)
+      --max-file-size <BYTES>      Largest uploaded file, 0 for no limit (default: 0)
+      --max-upload-parts <N>       Most files and fields in one upload form (default: 1000)
+      --min-free-space <BYTES>     Refuse uploads that would leave less free disk space (default: 64M)
+      --name-policy <POLICY>       Characters allowed in new names: any, printable, portable or windows (default: printable)
+      --etag <MODE>                Base ETags on file metadata or a content hash: metadata or hash (default: metadata)
+      --trash <BOOL>               Move deleted entries to a recycle bin instead of removing them (default: true)
+      --trash-retention <DAYS>     Purge entries from the recycle bin after DAYS, 0 to keep them (default: 30)
+  -h, --help                       Print this help
+
+Every option can also be set in the config file as `key = value` (e.g. `buffer_size = 16384`)
+or through the environment as FILESERVE_<KEY> (e.g. FILESERVE_PORT=8080).
+Sizes take an optional K, M, G or T suffix (e.g. `max_file_size = 2G`).
+MIME types are mapped per extension in a `[mime]` section (e.g. `md = text/plain`).
+Precedence: command line > environment > config file > defaults.";
+
+pub struct Config {
+  pub address           : String,
+  pub port              : u16,
+  pub root              : PathBuf,
+  pub static_dir        : Option<PathBuf>,
+  pub theme             : Option<PathBuf>,
+  pub workers           : usize,
+  pub buffer_size       : usize,
+  pub keep_alive_timeout: u64,
+  pub max_requests      : usize,
+  pub symlinks          : SymlinkPolicy,
+  pub name_policy       : NamePolicy,
+  pub upload_conflict   : ConflictPolicy,
+  /// 0 for no limit.
+  pub max_upload_size   : u64,
+  /// 0 for no limit.
+  pub max_file_size     : u64,
+  pub max_upload_parts  : usize,
+  pub min_free_space    : u64,
+  pub mime_types        : HashMap<String, String>,
+  pub etag              : EtagMode,
+  pub trash             : bool,
+  pub trash_retention   : u64
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      address           : "127.0.0.1".to_string(),
+      port              : 8000,
+      root              : PathBuf::from("files"),
+      static_dir        : None,
+      theme             : None,
+      workers           : 16,
+      buffer_size       : 8096,
+      keep_alive_timeout: 5,
+      max_requests      : 100,
+      symlinks          : SymlinkPolicy::FollowInsideRoot,
+      name_policy       : NamePolicy::Printable,
+      upload_conflict   : ConflictPolicy::Rename,
+      max_upload_size   : 0,
+      max_file_size     : 0,
+      max_upload_parts  : 1000,
+      min_free_space    : 64 << 20,
+      mime_types        : HashMap::new(),
+      etag              : EtagMode::Metadata,
+      trash             : true,
+      trash_retention   : 30
+    }
+  }
+}
+
+impl Config {
+  /// Builds the configuration from defaults, the config file, the environment and the command line (in that order).
+  /// Returns `Ok(None)` if only the usage was requested.
+  pub fn load<I>(args: I) -> Result<Option<Self>, ServerError>
+  where I: IntoIterator<Item = String> {
+    let cli = match parse_args(args)? {
+      Some(cli) => cli,
+      None      => { println!("{USAGE}"); return Ok(None) }
+    };
+
+    let mut config = Self::default();
+
+    let explicit_file = cli.iter()
+      .rev()
+      .find(|(key, _)| key == "config")
+      .map(|(_, value)| value.clone())
+      .or_else(|| std::env::var(CONFIG_ENV).ok());
+    match explicit_file {
+      Some(file) => config.apply_file(&file)?,
+      None       => if fs::metadata(DEFAULT_CONFIG).is_ok() { config.apply_file(DEFAULT_CONFIG)? }
+    }
+
+    // Other programs may use the prefix too, so unknown variables are only warned about
+    for (name, value) in std::env::vars_os() {
+      let (Some(name), Some(value)) = (name.to_str(), value.to_str()) else { continue };
+      if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+        if name != CONFIG_ENV && !config.try_set(&key.to_ascii_lowercase(), value)? {
+          println!("Config Warning: Ignoring unknown setting {name}");
+        }
+      }
+    }
+
+    for (key, value) in cli.iter().filter(|(key, _)| key != "config") {
+      config.set(key, value)?;
+    }
+
+    if config.buffer_size == 0 { return Err(ServerError::ConfigError("buffer_size must be larger than 0".to_string())) }
+    if config.keep_alive_timeout == 0 { return Err(ServerError::ConfigError("keep_alive_timeout must be larger than 0".to_string())) }
+    if config.max_requests == 0 { return Err(ServerError::ConfigError("max_requests must be larger than 0".to_string())) }
+
+    Ok(Some(config))
+  }
+
+  pub fn bind_address(&self) -> String {
+    if self.address.contains(':') && !self.address.starts_with('[') {
+      format!("[{}]:{}", self.address, self.port)
+    } else {
+      format!("{}:{}", self.address, self.port)
+    }
+  }
+
+  /// Reads an INI-style file: `key = value` lines, `#`/`;` comments and `[section]` headers.
+  /// Keys inside a section are addressed as `section.key`.
+  fn apply_file(&mut self, file: &str) -> Result<(), ServerError> {
+    let contents = fs::read_to_string(file)
+      .map_err(|e| ServerError::ConfigError(format!("Cannot read config file {file}: {e}")))?;
+    let mut section = String::new();
+
+    for (number, line) in contents.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') || line.starts_with(';') { continue }
+
+      if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+        section = name.trim().to_ascii_lowercase();
+      } else if let Some((key, value)) = line.split_once('=') {
+        let key   = key.trim().to_ascii_lowercase();
+        let value = value.trim().trim_matches('"');
+        let key   = if section.is_empty() || section == "server" { key } else { format!("{section}.{key}") };
+        self.set(&key, value)
+          .map_err(|e| ServerError::ConfigError(format!("{file}:{}: {e}", number+1)))?;
+      } else {
+        return Err(ServerError::ConfigError(format!("{file}:{}: Expected `key = value`, got `{line}`", number+1)))
+      }
+    }
+    Ok(())
+  }
+
+  fn set(&mut self, key: &str, value: &str) -> Result<(), ServerError> {
+    match self.try_set(key, value)? {
+      true  => Ok(()),
+      false => Err(ServerError::ConfigError(format!("Unknown setting `{key}`")))
+    }
+  }
+
+  /// Applies a setting. Returns false if there is no setting `key`.
+  fn try_set(&mut self, key: &str, value: &str) -> Result<bool, ServerError> {
+    match key.replace('-', "_").as_str() {
+      "address"            => self.address            = value.to_string(),
+      "port"               => self.port               = parse_number(key, value)?,
+      "root"               => self.root               = PathBuf::from(value),
+      "static"             => self.static_dir         = Some(PathBuf::from(value)),
+      "theme"              => self.theme              = Some(PathBuf::from(value)),
+      "workers"            => self.workers            = parse_number(key, value)?,
+      "buffer_size"        => self.buffer_size        = parse_number(key, value)?,
+      "keep_alive_timeout" => self.keep_alive_timeout = parse_number(key, value)?,
+      "max_requests"       => self.max_requests       = parse_number(key, value)?,
+      "symlinks"           => self.symlinks           = SymlinkPolicy::try_from(value)?,
+      "name_policy"        => self.name_policy        = NamePolicy::try_from(value)?,
+      "upload_conflict"    => self.upload_conflict    = ConflictPolicy::try_from(value)?,
+      "max_upload_size"    => self.max_upload_size    = parse_size(key, value)?,
+      "max_file_size"      => self.max_file_size      = parse_size(key, value)?,
+      "max_upload_parts"   => self.max_upload_parts   = parse_number(key, value)?,
+      "min_free_space"     => self.min_free_space     = parse_size(key, value)?,
+      "etag"               => self.etag               = EtagMode::try_from(value)?,
+      "trash"              => self.trash              = parse_bool(key, value)?,
+      "trash_retention"    => self.trash_retention    = parse_number(key, value)?,
+      other                => match other.strip_prefix("mime.") {
+        Some(extension) if value.contains('/') => {
+          self.mime_types.insert(extension.trim_start_matches('.').to_ascii_lowercase(), value.to_string());
+        },
+        Some(_) => return Err(ServerError::ConfigError(format!("Invalid MIME type `{value}` for `{key}`"))),
+        None    => return Ok(false)
+      }
+    }
+    Ok(true)
+  }
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ServerError> {
+  value.parse::<T>().map_err(|_| ServerError::ConfigError(format!("Invalid value `{value}` for `{key}`")))
+}
+
+/// A number of bytes with an optional binary unit, like `512`, `64K`, `10M` or `2GiB`.
+fn parse_size(key: &str, value: &str) -> Result<u64, ServerError> {
+  let upper = value.trim().to_ascii_uppercase();
+  let number = upper.strip_suffix("IB").or_else(|| upper.strip_suffix('B')).unwrap_or(&upper);
+  let (number, shift) = match number.char_indices().last() {
+    Some((i, 'K')) => (&number[..i], 10),
+    Some((i, 'M')) => (&number[..i], 20),
+    Some((i, 'G')) => (&number[..i], 30),
+    Some((i, 'T')) => (&number[..i], 40),
+    _              => (number, 0)
+  };
+  number.trim().parse::<u64>().ok()
+    .and_then(|number| number.checked_mul(1 << shift))
+    .ok_or_else(|| ServerError::ConfigError(format!("Invalid size `{value}` for `{key}`")))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, ServerError> {
+  match value.to_ascii_lowercase().as_str() {
+    "true"  | "yes" | "on"  | "1" => Ok(true),
+    "false" | "no"  | "off" | "0" => Ok(false),
+    _                             => Err(ServerError::ConfigError(format!("Invalid value `{value}` for `{key}` (expected true or false)")))
+  }
+}
+
+/// Turns `--key value`, `--key=value` and short flags into `(key, value)` pairs.
+/// Returns `Ok(None)` if `--help` was given.
+fn parse_args<I>(args: I) -> Result<Option<Vec<(String, String)>>, ServerError>
+where I: IntoIterator<Item = String> {
+  let mut pairs = Vec::new();
+  let mut args  = args.into_iter();
+
+  while let Some(arg) = args.next() {
+    let (flag, inline_value) = match arg.split_once('=') {
+      Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+      None                => (arg.clone(), None)
+    };
+    let key = match flag.as_str() {
+      "-h" | "--help" => return Ok(None),
+      "-c"            => "config".to_string(),
+      "-a"            => "address".to_string(),
+      "-p"            => "port".to_string(),
+      "-r"            => "root".to_string(),
+      "-w"            => "workers".to_string(),
+      long if long.starts_with("--") && long.len() > 2 => long[2..].replace('-', "_"),
+      _               => return Err(ServerError::ConfigError(format!("Unexpected argument `{arg}`\n\n{USAGE}")))
+    };
+    let value = match inline_value.or_else(|| args.next()) {
+      Some(value) => value,
+      None        => return Err(ServerError::ConfigError(format!("Missing value for `{flag}`")))
+    };
+    pairs.push((key, value));
+  }
+  Ok(Some(pairs))
+}
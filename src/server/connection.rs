@@ -0,0 +1,327 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+use super::error::ServerError;
+use super::{CRLF, HEADER_END};
+
+/// Longest chunk header or trailer field line accepted.
+const MAX_CHUNK_LINE: usize = 4 * 1024;
+const MAX_TRAILERS  : usize = 16 * 1024;
+
+/// A client connection that may carry several (pipelined) requests.
+/// Bytes read past the end of one request stay buffered for the next one.
+pub struct Connection {
+  stream     : TcpStream,
+  buffer     : Vec<u8>,
+  buffer_size: usize
+}
+
+impl Connection {
+  pub fn new(stream: TcpStream, buffer_size: usize) -> Self {
+    Self { stream, buffer: Vec::new(), buffer_size }
+  }
+
+  /// The socket itself, so responses can be copied into it without an extra buffer.
+  pub fn stream(&mut self) -> &mut TcpStream { &mut self.stream }
+
+  /// Waits up to `idle_timeout` for the next request and returns its head (without the empty line).
+  /// Returns `Ok(None)` if the client closed the connection or stayed idle before sending anything.
+  pub fn read_head(&mut self, idle_timeout: Duration, max_head_size: usize) -> Result<Option<Vec<u8>>, ServerError> {
+    self.stream.set_read_timeout(Some(idle_timeout))?;
+    let mut searched = 0;
+
+    loop {
+      // Clients may send empty lines between pipelined requests
+      while self.buffer.starts_with(&HEADER_END[..2]) { self.buffer.drain(..2); }
+
+      if let Some(cutoff) = self.buffer[searched..].windows(HEADER_END.len()).position(|w| w == HEADER_END).map(|p| p+searched) {
+        let head = self.buffer[..cutoff].to_vec();
+        self.buffer.drain(..cutoff+HEADER_END.len());
+        return Ok(Some(head))
+      }
+      searched = self.buffer.len().saturating_sub(HEADER_END.len()-1);
+
+      if self.buffer.len() > max_head_size {
+        return Err(ServerError::PayloadTooLarge(format!("Request header exceeds {max_head_size} bytes")))
+      }
+
+      match self.fill() {
+        Ok(0) if self.buffer.is_empty()                 => return Ok(None),
+        Ok(0)                                           => return Err(ServerError::BadRequest("Connection closed within the request header".to_string())),
+        Ok(_)                                           => (),
+        Err(e) if e.kind() == ErrorKind::Interrupted    => (),
+        Err(e) if self.buffer.is_empty()
+               && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+        Err(e)                                          => return Err(e.into())
+      }
+    }
+  }
+
+  /// Ends a connection whose client may still be sending a body that will not be read. Closing the socket with
+  /// unread data would reset the connection and could discard the response, so the write side is shut down
+  /// first and input is discarded until the client closes too, for at most `timeout`.
+  pub fn linger(&mut self, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    if self.stream.shutdown(Shutdown::Write).is_err() { return }
+    let mut chunk = vec![0; self.buffer_size];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+      if self.stream.set_read_timeout(Some(left)).is_err() { return }
+      match self.stream.read(&mut chunk) {
+        Ok(0) | Err(_) => return,
+        Ok(_)          => ()
+      }
+    }
+  }
+
+  /// Reads a line of at most `max_length` bytes and returns it without its CRLF.
+  fn read_line(&mut self, max_length: usize) -> io::Result<Vec<u8>> {
+    let mut searched = 0;
+    loop {
+      if let Some(end) = self.buffer[searched..].windows(CRLF.len()).position(|w| w == CRLF).map(|p| p+searched) {
+        if end > max_length { return Err(io::Error::new(ErrorKind::InvalidData, "Line is too long")) }
+        let line = self.buffer[..end].to_vec();
+        self.buffer.drain(..end+CRLF.len());
+        return Ok(line)
+      }
+      searched = self.buffer.len().saturating_sub(CRLF.len()-1);
+
+      if self.buffer.len() > max_length { return Err(io::Error::new(ErrorKind::InvalidData, "Line is too long")) }
+      match self.fill() {
+        Ok(0)                                        => return Err(ErrorKind::UnexpectedEof.into()),
+        Ok(_)                                        => (),
+        Err(e) if e.kind() == ErrorKind::Interrupted => (),
+        Err(e)                                       => return Err(e)
+      }
+    }
+  }
+
+  fn fill(&mut self) -> io::Result<usize> {
+    let mut chunk = vec![0; self.buffer_size];
+    let read = self.stream.read(&mut chunk)?;
+    self.buffer.extend_from_slice(&chunk[..read]);
+    Ok(read)
+  }
+}
+
+impl Read for Connection {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.buffer.is_empty() { return self.stream.read(buf) }
+    let read = buf.len().min(self.buffer.len());
+    buf[..read].copy_from_slice(&self.buffer[..read]);
+    self.buffer.drain(..read);
+    Ok(read)
+  }
+}
+
+impl Write for Connection {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.stream.write(buf) }
+  fn flush(&mut self) -> io::Result<()> { self.stream.flush() }
+}
+
+/// How the end of a request body is found: after `Content-Length` bytes, or at the last chunk.
+pub enum Framing { Length(u64), Chunked }
+
+enum State {
+  /// Bytes left of a body with `Content-Length`
+  Length(u64),
+  /// Bytes left of the current chunk, followed by a CRLF
+  Chunk(u64),
+  ChunkHeader,
+  Done
+}
+
+/// The body of a request, decoded from its framing. If the client sent `Expect: 100-continue`, it is told to go
+/// ahead only when the body is first read, so a request rejected before that is answered without the body being sent.
+/// Chunked bodies longer than `max_length` (0 for no limit) fail with `FileTooLarge`.
+pub struct RequestBody<'a> {
+  connection      : &'a mut Connection,
+  state           : State,
+  expects_continue: bool,
+  decoded         : u64,
+  max_length      : u64
+}
+
+impl<'a> RequestBody<'a> {
+  pub fn new(connection: &'a mut Connection, framing: Framing, expects_continue: bool, max_length: u64) -> Self {
+    let state = match framing {
+      Framing::Length(length) => State::Length(length),
+      Framing::Chunked        => State::ChunkHeader
+    };
+    Self { connection, state, expects_continue, decoded: 0, max_length }
+  }
+
+  /// Whether the body was read to its end, so the next request can follow on the connection.
+  pub fn finished(&self) -> bool {
+    matches!(self.state, State::Length(0) | State::Done)
+  }
+
+  /// Reads a chunk header (RFC 9112, 7.1), ignoring chunk extensions, or the trailer section after the last chunk.
+  fn next_chunk(&mut self) -> io::Result<()> {
+    let line = self.connection.read_line(MAX_CHUNK_LINE)?;
+    let line = String::from_utf8_lossy(&line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+    // `from_str_radix` would also take a sign
+    let size = Some(size).filter(|size| !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()))
+      .and_then(|size| u64::from_str_radix(size, 16).ok())
+      .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("Invalid chunk size `{size}`")))?;
+    if size > 0 {
+      self.state = State::Chunk(size);
+      return Ok(())
+    }
+
+    let mut trailers = 0;
+    loop {
+      let line = self.connection.read_line(MAX_CHUNK_LINE)?;
+      if line.is_empty() { break }
+      trailers += line.len();
+      if trailers > MAX_TRAILERS { return Err(io::Error::new(ErrorKind::InvalidData, "Trailer section is too large")) }
+      match String::from_utf8_lossy(&line).split_once(':') {
+        Some((name, value)) => println!("Trailer {}: {}", name.trim(), value.trim()),
+        None                => return Err(io::Error::new(ErrorKind::InvalidData, "Malformed trailer field"))
+      }
+    }
+    self.state = State::Done;
+    Ok(())
+  }
+}
+
+impl Read for RequestBody<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.expects_continue {
+      self.expects_continue = false;
+      if !self.finished() {
+        let stream = self.connection.stream();
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        stream.flush()?;
+      }
+    }
+
+    loop {
+      match self.state {
+        State::Length(0) | State::Done => return Ok(0),
+        State::Length(left)            => {
+          let wanted = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+          let read = self.connection.read(&mut buf[..wanted])?;
+          self.state = State::Length(left - read as u64);
+          return Ok(read)
+        },
+        State::Chunk(0)                => {
+          if !self.connection.read_line(CRLF.len())?.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Chunk is longer than its size"))
+          }
+          self.state = State::ChunkHeader;
+        },
+        State::Chunk(left)             => {
+          let wanted = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+          let read = self.connection.read(&mut buf[..wanted])?;
+          if read == 0 && !buf.is_empty() { return Err(ErrorKind::UnexpectedEof.into()) }
+          self.decoded += read as u64;
+          if self.max_length > 0 && self.decoded > self.max_length {
+            return Err(io::Error::new(ErrorKind::FileTooLarge, format!("Request body is larger than the limit of {} bytes", self.max_length)))
+          }
+          self.state = State::Chunk(left - read as u64);
+          return Ok(read)
+        },
+        State::ChunkHeader             => self.next_chunk()?
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::TcpListener;
+
+  /// A connection whose client has sent `input` and then closed its write side. The client end is returned too,
+  /// so what the server writes back can be read.
+  fn connection(input: &[u8]) -> (Connection, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client.write_all(input).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    (Connection::new(stream, 7), client)
+  }
+
+  fn read_chunked(input: &[u8], max_length: u64) -> io::Result<Vec<u8>> {
+    let (mut connection, _client) = connection(input);
+    let mut decoded = Vec::new();
+    RequestBody::new(&mut connection, Framing::Chunked, false, max_length).read_to_end(&mut decoded)?;
+    Ok(decoded)
+  }
+
+  fn invalid(result: io::Result<Vec<u8>>) -> bool {
+    result.is_err_and(|e| e.kind() == ErrorKind::InvalidData)
+  }
+
+  #[test]
+  fn decodes_chunks_and_keeps_the_next_request() {
+    let input = b"5;name=\"a;b\"\r\nhello\r\nA \r\n, chunked!\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+    let (mut connection, _client) = connection(input);
+    let mut body = RequestBody::new(&mut connection, Framing::Chunked, false, 0);
+    let mut decoded = Vec::new();
+    let mut byte = [0; 1];
+    // One byte at a time, so chunk boundaries fall between reads
+    while body.read(&mut byte).unwrap() > 0 { decoded.push(byte[0]) }
+    assert_eq!(decoded, b"hello, chunked!");
+    assert!(body.finished());
+    assert_eq!(connection.read_head(Duration::from_secs(1), 1024).unwrap().unwrap(), b"GET /next HTTP/1.1");
+  }
+
+  #[test]
+  fn reads_bodies_of_known_length() {
+    let (mut connection, _client) = connection(b"abcdefGET / HTTP/1.1\r\n\r\n");
+    let mut body = RequestBody::new(&mut connection, Framing::Length(6), false, 0);
+    let mut decoded = Vec::new();
+    body.read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, b"abcdef");
+    assert!(body.finished());
+    assert_eq!(connection.read_head(Duration::from_secs(1), 1024).unwrap().unwrap(), b"GET / HTTP/1.1");
+  }
+
+  #[test]
+  fn rejects_invalid_chunk_sizes() {
+    for size in ["", " ", "g", "+5", "-1", "0x5", "5 5", "10000000000000000"] {
+      assert!(invalid(read_chunked(format!("{size}\r\nhello\r\n0\r\n\r\n").as_bytes(), 0)), "{size}");
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_chunks() {
+    // Data longer than announced, i.e. no CRLF right after the chunk
+    assert!(invalid(read_chunked(b"3\r\nhello\r\n0\r\n\r\n", 0)));
+    assert!(invalid(read_chunked(b"0\r\nno colon\r\n\r\n", 0)));
+    assert!(invalid(read_chunked(format!("{}\r\n", "0".repeat(MAX_CHUNK_LINE + 1)).as_bytes(), 0)));
+    let trailers = format!("0\r\n{}\r\n", "X-Pad: 0123456789abcdef\r\n".repeat(MAX_TRAILERS / 20));
+    assert!(invalid(read_chunked(trailers.as_bytes(), 0)));
+  }
+
+  #[test]
+  fn rejects_truncated_bodies() {
+    for input in [&b"5\r\nhel"[..], b"5\r\nhello", b"5\r\nhello\r\n", b"5\r\nhello\r\n0\r\n", b"5"] {
+      assert!(read_chunked(input, 0).is_err_and(|e| e.kind() == ErrorKind::UnexpectedEof), "{}", String::from_utf8_lossy(input));
+    }
+  }
+
+  #[test]
+  fn limits_the_decoded_length() {
+    let input = b"4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n";
+    assert_eq!(read_chunked(input, 8).unwrap(), b"abcdefgh");
+    assert!(read_chunked(input, 7).is_err_and(|e| e.kind() == ErrorKind::FileTooLarge));
+  }
+
+  #[test]
+  fn sends_continue_only_when_the_body_is_read() {
+    let (mut connection, mut client) = connection(b"abc");
+    let mut decoded = Vec::new();
+    RequestBody::new(&mut connection, Framing::Length(3), true, 0).read_to_end(&mut decoded).unwrap();
+    RequestBody::new(&mut connection, Framing::Length(0), true, 0).read_to_end(&mut decoded).unwrap();
+    drop(connection);
+    let mut answer = Vec::new();
+    client.read_to_end(&mut answer).unwrap();
+    assert_eq!(decoded, b"abc");
+    assert_eq!(answer, b"HTTP/1.1 100 Continue\r\n\r\n");
+  }
+}
@@ -0,0 +1,99 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS  : [&str; 7]  = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Converts days since 1970-01-01 to (year, month, day) in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z   = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+  let doy = doe - (365*yoe + yoe/4 - yoe/100);
+  let mp  = (5*doy + 2) / 153;
+  let day   = (doy - (153*mp + 2)/5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  (yoe + era*400 + i64::from(month <= 2), month, day)
+}
+
+/// Converts (year, month, day) to days since 1970-01-01.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era  = year.div_euclid(400);
+  let yoe  = year.rem_euclid(400);
+  let mp   = i64::from((month + 9) % 12);
+  let doy  = (153*mp + 2)/5 + i64::from(day) - 1;
+  let doe  = yoe*365 + yoe/4 - yoe/100 + doy;
+  era*146097 + doe - 719468
+}
+
+/// Formats a timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format(time: SystemTime) -> String {
+  let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+  let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+  let (year, month, day) = civil_from_days(days);
+  format!("{}, {day:02} {} {year:04} {:02}:{:02}:{:02} GMT",
+    DAYS[days.rem_euclid(7) as usize], MONTHS[month as usize - 1], rest/3600, rest%3600/60, rest%60)
+}
+
+/// Parses an IMF-fixdate. The obsolete RFC 850 and asctime formats are not supported.
+pub fn parse(value: &str) -> Option<SystemTime> {
+  let parts = value.trim().split(' ').collect::<Vec<&str>>();
+  if parts.len() != 6 || parts[5] != "GMT" { return None }
+
+  let day   = parts[1].parse::<u32>().ok()?;
+  let month = MONTHS.iter().position(|m| *m == parts[2])? as u32 + 1;
+  let year  = parts[3].parse::<i64>().ok().filter(|year| (1..=9999).contains(year))?;
+  let time  = parts[4].split(':').map(|t| t.parse::<u64>().ok()).collect::<Option<Vec<u64>>>()?;
+  if time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 || !(1..=31).contains(&day) { return None }
+
+  let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+  let secs = days.checked_mul(86400)?.checked_add(time[0]*3600 + time[1]*60 + time[2])?;
+  UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Truncates a timestamp to whole seconds, the resolution of HTTP dates.
+pub fn truncate(time: SystemTime) -> SystemTime {
+  UNIX_EPOCH + Duration::from_secs(time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+}
+
+/// Formats a timestamp for people, e.g. `1994-11-06 08:49 UTC`.
+pub fn format_short(time: SystemTime) -> String {
+  let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+  let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+  let (year, month, day) = civil_from_days(days);
+  format!("{year:04}-{month:02}-{day:02} {:02}:{:02} UTC", rest/3600, rest%3600/60)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn formats_and_parses_http_dates() {
+    let time = UNIX_EPOCH + Duration::from_secs(784111777);
+    assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+    assert_eq!(parse(" Thu, 01 Jan 1970 00:00:00 GMT "), Some(UNIX_EPOCH));
+    assert_eq!(parse("Fri, 31 Dec 9999 23:59:59 GMT").map(format).as_deref(), Some("Fri, 31 Dec 9999 23:59:59 GMT"));
+    for secs in [0, 951782400, 1709164800, 4107542399] {
+      let time = UNIX_EPOCH + Duration::from_secs(secs);
+      assert_eq!(parse(&format(time)), Some(time));
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_and_out_of_range_dates() {
+    for value in [
+      "", "GMT", "Sun, 06 Nov 1994 08:49:37", "Sun, 06 Nov 1994 08:49:37 UTC", "Sunday, 06-Nov-94 08:49:37 GMT",
+      "Sun Nov  6 08:49:37 1994", "Sun, 06 nov 1994 08:49:37 GMT", "Sun, 32 Nov 1994 08:49:37 GMT",
+      "Sun, 00 Nov 1994 08:49:37 GMT", "Sun, 06 Nov 1994 24:00:00 GMT", "Sun, 06 Nov 1994 08:60:00 GMT",
+      "Sun, 06 Nov 1994 08:49 GMT", "Sun, 06 Nov 1994 08:49:37:00 GMT", "Sun, 06 Nov -1994 08:49:37 GMT",
+      "Thu, 01 Jan 1969 23:59:59 GMT", "Thu, 01 Jan 0 00:00:00 GMT", "Thu, 01 Jan 10000 00:00:00 GMT",
+      "Thu, 01 Jan 300000000000 00:00:00 GMT", "Thu, 01 Jan 9223372036854775807 00:00:00 GMT",
+      "Thu, 01 Jan 1970 99999999999999999999:00:00 GMT"
+    ] {
+      assert_eq!(parse(value), None, "{value}");
+    }
+  }
+}
@@ -1,30 +1,109 @@
 use std::{io, string, fmt, error, num};
 
+use super::response::Status;
+
+#[allow(clippy::enum_variant_names)]
 pub enum ServerError {
   TransportError(io::Error),
   ConvertError(string::FromUtf8Error),
   ParseIntError(num::ParseIntError),
-  HTTPParseError(String)
+  HTTPParseError(String),
+  ConfigError(String),
+  NotFound(String),
+  Forbidden(String),
+  Conflict(String),
+  PreconditionFailed(String),
+  PayloadTooLarge(String),
+  ExpectationFailed(String),
+  BadRequest(String),
+  MethodNotAllowed(String),
+  Internal(String)
 }
 
-impl fmt::Debug for ServerError {
-  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl ServerError {
+  /// The HTTP status a client receives when its request fails with this error.
+  pub fn status(&self) -> Status {
     match self {
-      Self::TransportError(e) => write!(f, "TransportError {}",  e),
-      Self::ConvertError(e)   => write!(f, "ConvertError {}"  ,  e),
-      Self::ParseIntError(e)  => write!(f, "ParseIntError {}" ,  e),
-      Self::HTTPParseError(e) => write!(f, "HTTPParseError {}",  e)
+      Self::TransportError(e) => match e.kind() {
+        io::ErrorKind::NotFound
+        | io::ErrorKind::NotADirectory     => Status::NotFound,
+        io::ErrorKind::PermissionDenied    => Status::Forbidden,
+        io::ErrorKind::AlreadyExists
+        | io::ErrorKind::DirectoryNotEmpty
+        | io::ErrorKind::IsADirectory      => Status::Conflict,
+        io::ErrorKind::InvalidInput
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::InvalidFilename
+        | io::ErrorKind::UnexpectedEof     => Status::BadRequest,
+        io::ErrorKind::TimedOut
+        | io::ErrorKind::WouldBlock        => Status::RequestTimeout,
+        io::ErrorKind::FileTooLarge        => Status::PayloadTooLarge,
+        io::ErrorKind::StorageFull         => Status::InsufficientStorage,
+        _                                  => Status::InternalServerError
+      },
+      Self::ConvertError(_)
+      | Self::ParseIntError(_)
+      | Self::HTTPParseError(_)
+      | Self::BadRequest(_)        => Status::BadRequest,
+      Self::NotFound(_)            => Status::NotFound,
+      Self::Forbidden(_)           => Status::Forbidden,
+      Self::Conflict(_)            => Status::Conflict,
+      Self::PreconditionFailed(_)  => Status::PreconditionFailed,
+      Self::PayloadTooLarge(_)     => Status::PayloadTooLarge,
+      Self::ExpectationFailed(_)   => Status::ExpectationFailed,
+      Self::MethodNotAllowed(_)    => Status::MethodNotAllowed,
+      Self::ConfigError(_)
+      | Self::Internal(_)          => Status::InternalServerError
+    }
+  }
+
+  /// The explanation a client receives. Internal failures are not exposed.
+  pub fn public_message(&self) -> String {
+    match self.status() {
+      Status::InternalServerError => Status::InternalServerError.reason().to_string(),
+      _ => match self {
+        Self::TransportError(e)   => e.to_string(),
+        Self::ConvertError(e)     => e.to_string(),
+        Self::ParseIntError(e)    => e.to_string(),
+        Self::HTTPParseError(e)
+        | Self::ConfigError(e)
+        | Self::NotFound(e)
+        | Self::Forbidden(e)
+        | Self::Conflict(e)
+        | Self::PreconditionFailed(e)
+        | Self::PayloadTooLarge(e)
+        | Self::ExpectationFailed(e)
+        | Self::BadRequest(e)
+        | Self::MethodNotAllowed(e)
+        | Self::Internal(e)       => e.clone()
+      }
     }
   }
 }
 
+impl fmt::Debug for ServerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
 impl fmt::Display for ServerError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match self {
-      Self::TransportError(e) => write!(f, "TransportError {}",  e),
-      Self::ConvertError(e)   => write!(f, "ConvertError {}"  ,  e),
-      Self::ParseIntError(e)  => write!(f, "ParseIntError {}" ,  e),
-      Self::HTTPParseError(e) => write!(f, "HTTPParseError {}",  e)
+      Self::TransportError(e)     => write!(f, "TransportError {}"    , e),
+      Self::ConvertError(e)       => write!(f, "ConvertError {}"      , e),
+      Self::ParseIntError(e)      => write!(f, "ParseIntError {}"     , e),
+      Self::HTTPParseError(e)     => write!(f, "HTTPParseError {}"    , e),
+      Self::ConfigError(e)        => write!(f, "ConfigError {}"       , e),
+      Self::NotFound(e)           => write!(f, "NotFound {}"          , e),
+      Self::Forbidden(e)          => write!(f, "Forbidden {}"         , e),
+      Self::Conflict(e)           => write!(f, "Conflict {}"          , e),
+      Self::PreconditionFailed(e) => write!(f, "PreconditionFailed {}", e),
+      Self::PayloadTooLarge(e)    => write!(f, "PayloadTooLarge {}"   , e),
+      Self::ExpectationFailed(e)  => write!(f, "ExpectationFailed {}" , e),
+      Self::BadRequest(e)         => write!(f, "BadRequest {}"        , e),
+      Self::MethodNotAllowed(e)   => write!(f, "MethodNotAllowed {}"  , e),
+      Self::Internal(e)           => write!(f, "Internal {}"          , e)
     }
   }
 }
@@ -35,7 +114,7 @@ impl error::Error for ServerError {
       Self::TransportError(ref e) => Some(e),
       Self::ConvertError(ref e)   => Some(e),
       Self::ParseIntError(ref e)  => Some(e),
-      Self::HTTPParseError(_)     => None
+      _                           => None
     }
   }
 }
@@ -50,4 +129,4 @@ impl From<string::FromUtf8Error> for ServerError {
 
 impl From<num::ParseIntError> for ServerError {
   fn from(e: num::ParseIntError) -> Self { Self::ParseIntError(e) }
-}
\ No newline at end of file
+}
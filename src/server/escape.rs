@@ -0,0 +1,51 @@
+/// Escapes text for use inside HTML element content or a quoted attribute value.
+pub fn html(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '&'  => escaped.push_str("&amp;"),
+      '<'  => escaped.push_str("&lt;"),
+      '>'  => escaped.push_str("&gt;"),
+      '"'  => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      _    => escaped.push(c)
+    }
+  }
+  escaped
+}
+
+/// Escapes text for use inside a JSON string literal (without the surrounding quotes).
+pub fn json(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '"'                  => escaped.push_str("\\\""),
+      '\\'                 => escaped.push_str("\\\\"),
+      '\n'                 => escaped.push_str("\\n"),
+      '\r'                 => escaped.push_str("\\r"),
+      '\t'                 => escaped.push_str("\\t"),
+      c if (c as u32) < 32 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+      _                    => escaped.push(c)
+    }
+  }
+  escaped
+}
+
+/// Escapes text for use inside a single- or double-quoted JavaScript string literal.
+/// Markup-significant characters are escaped too, so the literal can't end a `<script>` element;
+/// inside an event handler attribute the result must still go through `html`.
+pub fn js(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\\'                                           => escaped.push_str("\\\\"),
+      '\n'                                           => escaped.push_str("\\n"),
+      '\r'                                           => escaped.push_str("\\r"),
+      '\'' | '"' | '`' | '<' | '>' | '&' | '=' | '/' => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+      '\u{2028}' | '\u{2029}'                        => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+      c if (c as u32) < 32                           => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+      _                                              => escaped.push(c)
+    }
+  }
+  escaped
+}
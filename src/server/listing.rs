@@ -0,0 +1,237 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::date;
+use super::error::ServerError;
+use super::escape;
+use super::path;
+use super::template::Context;
+use super::mime;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind { Directory, File }
+
+impl EntryKind {
+  fn as_str(&self) -> &'static str {
+    match self {
+      EntryKind::Directory => "directory",
+      EntryKind::File      => "file"
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortKey { Name, Size, Modified }
+
+impl SortKey {
+  pub fn parse(value: Option<&str>) -> Self {
+    match value {
+      Some("size")  => SortKey::Size,
+      Some("mtime") => SortKey::Modified,
+      _             => SortKey::Name
+    }
+  }
+
+  fn as_str(&self) -> &'static str {
+    match self {
+      SortKey::Name     => "name",
+      SortKey::Size     => "size",
+      SortKey::Modified => "mtime"
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Order { Asc, Desc }
+
+impl Order {
+  pub fn parse(value: Option<&str>) -> Self {
+    match value {
+      Some("desc") => Order::Desc,
+      _            => Order::Asc
+    }
+  }
+
+  fn as_str(&self) -> &'static str {
+    match self {
+      Order::Asc  => "asc",
+      Order::Desc => "desc"
+    }
+  }
+}
+
+/// Renders a byte count with binary prefixes, e.g. `1.5 KiB`.
+pub fn human_size(bytes: u64) -> String {
+  const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+  if bytes < 1024 { return format!("{bytes} B") }
+  let (mut size, mut unit) = (bytes as f64 / 1024.0, 0);
+  while size >= 1024.0 && unit < UNITS.len()-1 { size /= 1024.0; unit += 1; }
+  format!("{size:.1} {}", UNITS[unit])
+}
+
+fn plural(count: usize, singular: &str, plural: &str) -> String {
+  format!("{count} {}", if count == 1 { singular } else { plural })
+}
+
+pub struct Entry {
+  pub name          : OsString,
+  pub kind          : EntryKind,
+  pub size          : u64,
+  pub items         : Option<usize>,
+  pub modified      : Option<SystemTime>,
+  pub permissions   : u32,
+  pub symlink_target: Option<PathBuf>,
+  pub mime_type     : Option<String>
+}
+
+impl Entry {
+  /// `rwxr-xr-x` style rendering of the permission bits.
+  pub fn permission_string(&self) -> String {
+    (0..9).rev().map(|bit| if self.permissions & (1 << bit) == 0 { '-' } else { ['x', 'w', 'r'][bit % 3] }).collect()
+  }
+
+  fn to_json(&self) -> String {
+    let optional = |value: Option<String>| value.map(|v| format!("\"{}\"", escape::json(&v))).unwrap_or("null".to_string());
+    format!(
+      "{{\"name\":\"{}\",\"href\":\"{}\",\"kind\":\"{}\",\"size\":{},\"items\":{},\"modified\":{},\"permissions\":\"{}\",\"symlink_target\":{},\"mime_type\":{}}}",
+      escape::json(&self.name.to_string_lossy()),
+      path::encode_url(&self.name),
+      self.kind.as_str(),
+      self.size,
+      self.items.map(|items| items.to_string()).unwrap_or("null".to_string()),
+      self.modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs().to_string()).unwrap_or("null".to_string()),
+      self.permission_string(),
+      optional(self.symlink_target.as_ref().map(|t| t.to_string_lossy().to_string())),
+      optional(self.mime_type.clone()))
+  }
+}
+
+/// The contents of a directory: directories first, then files, each sorted case-insensitively by name
+/// unless `sort` is called.
+pub struct Listing {
+  pub entries: Vec<Entry>
+}
+
+impl Listing {
+  pub fn read(path: &Path, mime_types: &HashMap<String, String>) -> Result<Self, ServerError> {
+    let mut entries = fs::read_dir(path)?.fold(Vec::new(), |mut acc, r_entry| {
+      match r_entry {
+        Ok(entry) => {
+          let symlink_target = fs::read_link(entry.path()).ok();
+          // Symlinks are listed as what they point to
+          match fs::metadata(entry.path()) {
+            Ok(md) if md.is_dir() || md.is_file() => {
+              let kind = if md.is_dir() { EntryKind::Directory } else { EntryKind::File };
+              acc.push(Entry {
+                mime_type     : if kind == EntryKind::File { mime::from_path(&entry.path(), mime_types).map(str::to_string) } else { None },
+                name          : entry.file_name(),
+                kind,
+                size          : md.len(),
+                items         : if kind == EntryKind::Directory { fs::read_dir(entry.path()).ok().map(|d| d.count()) } else { None },
+                modified      : md.modified().ok(),
+                permissions   : md.permissions().mode() & 0o777,
+                symlink_target
+              })
+            },
+            Ok(_)  => println!("List Dir Error: {:?} is neither file nor dir", entry.file_name()),
+            Err(e) => println!("List Dir Error: {e}")
+          }
+          acc
+        },
+        Err(e)    => { println!("List Dir Error: {e}"); acc }
+      }
+    });
+
+    entries.sort_by_key(|entry| (entry.kind != EntryKind::Directory, entry.name.as_bytes().to_ascii_lowercase()));
+    Ok(Self { entries })
+  }
+
+  /// Writes the listing as JSON one entry at a time, so large directories need not be serialized as a whole.
+  pub fn write_json(&self, url_path: &str, out: &mut dyn Write) -> io::Result<()> {
+    write!(out, "{{\"path\":\"{}\",\"entries\":[", escape::json(url_path))?;
+    for (i, entry) in self.entries.iter().enumerate() {
+      if i > 0 { out.write_all(b",")? }
+      out.write_all(entry.to_json().as_bytes())?;
+    }
+    out.write_all(b"]}")
+  }
+
+  /// Sorts directories and files separately, so directories stay on top. Ties are broken by name.
+  pub fn sort(&mut self, key: SortKey, order: Order) {
+    self.entries.sort_by(|a, b| {
+      let by_name = a.name.as_bytes().to_ascii_lowercase().cmp(&b.name.as_bytes().to_ascii_lowercase());
+      let by_key  = match key {
+        SortKey::Name     => by_name,
+        SortKey::Size     => a.items.unwrap_or_default().cmp(&b.items.unwrap_or_default()).then(a.size.cmp(&b.size)).then(by_name),
+        SortKey::Modified => a.modified.cmp(&b.modified).then(by_name)
+      };
+      let by_key = if order == Order::Desc { by_key.reverse() } else { by_key };
+      match (a.kind, b.kind) {
+        (EntryKind::Directory, EntryKind::File) => Ordering::Less,
+        (EntryKind::File, EntryKind::Directory) => Ordering::Greater,
+        _                                       => by_key
+      }
+    });
+  }
+
+  /// E.g. `2 directories, 3 files (1.2 KiB)`.
+  pub fn summary(&self) -> String {
+    let dirs  = self.entries.iter().filter(|e| e.kind == EntryKind::Directory).count();
+    let files = self.entries.len() - dirs;
+    let size  = self.entries.iter().filter(|e| e.kind == EntryKind::File).map(|e| e.size).sum::<u64>();
+    format!("{}, {} ({})", plural(dirs, "directory", "directories"), plural(files, "file", "files"), human_size(size))
+  }
+
+  /// The values the listing templates are rendered with. `url_path` is the percent-encoded request path.
+  pub fn context(&self, url_path: &str, is_root: bool, key: SortKey, order: Order) -> Context {
+    let mut href = String::from("/");
+    let mut breadcrumbs = vec![Context::from([("name", "files".into()), ("href", "/".into()), ("separator", false.into())])];
+    for segment in url_path.split('/').filter(|segment| !segment.is_empty()) {
+      let name = path::decode_url(segment);
+      href.push_str(&path::encode_url(&name));
+      href.push('/');
+      breadcrumbs.push(Context::from([("name", name.to_string_lossy().into()), ("href", href.clone().into()), ("separator", true.into())]));
+    }
+
+    // Column headers link to the listing sorted by that column, toggling the order of the current one
+    let columns = [("Name", SortKey::Name), ("Size", SortKey::Size), ("Modified", SortKey::Modified)].iter().map(|(label, column)| {
+      let (next, arrow) = match (*column == key, order) {
+        (true, Order::Asc)  => (Order::Desc, " ▲"),
+        (true, Order::Desc) => (Order::Asc, " ▼"),
+        (false, _)          => (Order::Asc, "")
+      };
+      Context::from([
+        ("label", (*label).into()),
+        ("arrow", arrow.into()),
+        ("href" , format!("?sort={}&order={}", column.as_str(), next.as_str()).into())
+      ])
+    }).collect::<Vec<Context>>();
+
+    // Hrefs carry the exact bytes of the name, `name` is only for display
+    let entries = self.entries.iter().map(|entry| Context::from([
+      ("name"        , entry.name.to_string_lossy().into()),
+      ("href"        , path::encode_url(&entry.name).into()),
+      ("is_directory", (entry.kind == EntryKind::Directory).into()),
+      ("size"        , entry.items.map(|items| plural(items, "item", "items")).unwrap_or_else(|| human_size(entry.size)).into()),
+      ("modified"    , entry.modified.map(date::format_short).unwrap_or_default().into()),
+      ("permissions" , entry.permission_string().into()),
+      ("mime_type"   , entry.mime_type.clone().unwrap_or_default().into())
+    ])).collect::<Vec<Context>>();
+
+    Context::from([
+      ("path"       , path::decode_url(url_path).to_string_lossy().into()),
+      ("breadcrumbs", breadcrumbs.into()),
+      ("parent"     , (!is_root).into()),
+      ("columns"    , columns.into()),
+      ("entries"    , entries.into()),
+      ("summary"    , self.summary().into())
+    ])
+  }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Number of bytes `sniff` needs to look at.
+pub const SNIFF_LENGTH: usize = 512;
+
+const TYPES: &[(&str, &str)] = &[
+  ("html"       , "text/html"),
+  ("htm"        , "text/html"),
+  ("css"        , "text/css"),
+  ("js"         , "text/javascript"),
+  ("mjs"        , "text/javascript"),
+  ("json"       , "application/json"),
+  ("webmanifest", "application/manifest+json"),
+  ("xml"        , "application/xml"),
+  ("txt"        , "text/plain"),
+  ("log"        , "text/plain"),
+  ("ini"        , "text/plain"),
+  ("conf"       , "text/plain"),
+  ("rs"         , "text/plain"),
+  ("toml"       , "text/plain"),
+  ("yaml"       , "text/plain"),
+  ("yml"        , "text/plain"),
+  ("sh"         , "text/plain"),
+  ("md"         , "text/markdown"),
+  ("csv"        , "text/csv"),
+  ("tsv"        , "text/tab-separated-values"),
+  ("ics"        , "text/calendar"),
+  ("png"        , "image/png"),
+  ("jpg"        , "image/jpeg"),
+  ("jpeg"       , "image/jpeg"),
+  ("gif"        , "image/gif"),
+  ("webp"       , "image/webp"),
+  ("avif"       , "image/avif"),
+  ("bmp"        , "image/bmp"),
+  ("ico"        , "image/x-icon"),
+  ("svg"        , "image/svg+xml"),
+  ("tif"        , "image/tiff"),
+  ("tiff"       , "image/tiff"),
+  ("mp3"        , "audio/mpeg"),
+  ("wav"        , "audio/wav"),
+  ("ogg"        , "audio/ogg"),
+  ("oga"        , "audio/ogg"),
+  ("opus"       , "audio/opus"),
+  ("flac"       , "audio/flac"),
+  ("m4a"        , "audio/mp4"),
+  ("mp4"        , "video/mp4"),
+  ("m4v"        , "video/mp4"),
+  ("webm"       , "video/webm"),
+  ("ogv"        , "video/ogg"),
+  ("mkv"        , "video/x-matroska"),
+  ("mov"        , "video/quicktime"),
+  ("avi"        , "video/x-msvideo"),
+  ("pdf"        , "application/pdf"),
+  ("zip"        , "application/zip"),
+  ("gz"         , "application/gzip"),
+  ("tgz"        , "application/gzip"),
+  ("tar"        , "application/x-tar"),
+  ("xz"         , "application/x-xz"),
+  ("zst"        , "application/zstd"),
+  ("7z"         , "application/x-7z-compressed"),
+  ("iso"        , "application/x-iso9660-image"),
+  ("wasm"       , "application/wasm"),
+  ("woff"       , "font/woff"),
+  ("woff2"      , "font/woff2"),
+  ("ttf"        , "font/ttf"),
+  ("otf"        , "font/otf"),
+  ("doc"        , "application/msword"),
+  ("docx"       , "application/vnd.openxmlformats-officedocument.wordprocessingml.document")
+];
+
+/// Signatures at the start of a file, checked in order.
+const MAGIC: &[(&[u8], &str)] = &[
+  (b"\x89PNG\r\n\x1a\n"   , "image/png"),
+  (b"\xff\xd8\xff"         , "image/jpeg"),
+  (b"GIF87a"               , "image/gif"),
+  (b"GIF89a"               , "image/gif"),
+  (b"\x00\x00\x01\x00"     , "image/x-icon"),
+  (b"%PDF-"                , "application/pdf"),
+  (b"PK\x03\x04"           , "application/zip"),
+  (b"\x1f\x8b"             , "application/gzip"),
+  (b"7z\xbc\xaf\x27\x1c"   , "application/x-7z-compressed"),
+  (b"\x1a\x45\xdf\xa3"     , "video/webm"),
+  (b"OggS"                 , "audio/ogg"),
+  (b"fLaC"                 , "audio/flac"),
+  (b"ID3"                  , "audio/mpeg"),
+  (b"\x00asm"              , "application/wasm"),
+  (b"wOFF"                 , "font/woff")
+];
+
+/// Looks the type up by file extension, preferring the user-configured `custom` mapping.
+pub fn from_path<'a>(path: &Path, custom: &'a HashMap<String, String>) -> Option<&'a str> {
+  let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+  custom
+    .get(&extension)
+    .map(|t| t.as_str())
+    .or_else(|| TYPES.iter().find(|(e, _)| *e == extension).map(|(_, t)| *t))
+}
+
+/// Guesses the type from the first bytes of a file.
+pub fn sniff(bytes: &[u8]) -> &'static str {
+  let bytes = &bytes[..bytes.len().min(SNIFF_LENGTH)];
+  if let Some((_, t)) = MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic)) { return t }
+
+  match (bytes.get(..4), bytes.get(8..12)) {
+    (Some(b"RIFF"), Some(b"WEBP")) => return "image/webp",
+    (Some(b"RIFF"), Some(b"WAVE")) => return "audio/wav",
+    (Some(b"RIFF"), Some(b"AVI ")) => return "video/x-msvideo",
+    _                              => ()
+  }
+  if bytes.get(4..8) == Some(b"ftyp") { return "video/mp4" }
+  // Plenty of text starts with "BM", so the size of the DIB header after the file header has to match one as well
+  let dib_header_size = bytes.get(14..18).map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]));
+  if bytes.starts_with(b"BM") && dib_header_size.is_some_and(|size| [12, 40, 52, 56, 64, 108, 124].contains(&size)) {
+    return "image/bmp"
+  }
+
+  // Text if it is UTF-8 (allowing a sequence cut off at the end) without control bytes other than whitespace
+  let is_text = match std::str::from_utf8(bytes) {
+    Ok(_)  => true,
+    Err(e) => e.error_len().is_none()
+  } && !bytes.iter().any(|b| *b < 0x20 && !b"\t\n\r\x0c".contains(b));
+  if !is_text { return OCTET_STREAM }
+
+  let start = String::from_utf8_lossy(bytes).trim_start().to_ascii_lowercase();
+  if start.starts_with("<!doctype html") || start.starts_with("<html") {
+    "text/html"
+  } else if start.starts_with("<svg") || (start.starts_with("<?xml") && start.contains("<svg")) {
+    "image/svg+xml"
+  } else if start.starts_with("<?xml") {
+    "application/xml"
+  } else {
+    "text/plain"
+  }
+}
+
+/// Adds `charset=utf-8` to textual types, since every text this server generates or expects is UTF-8.
+pub fn with_charset(mime_type: &str) -> String {
+  let is_text = mime_type.starts_with("text/")
+    || mime_type.ends_with("+json")
+    || mime_type.ends_with("+xml")
+    || ["application/json", "application/xml", "application/javascript"].contains(&mime_type);
+  if is_text && !mime_type.contains("charset=") {
+    format!("{mime_type}; charset=utf-8")
+  } else {
+    mime_type.to_string()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sniffs_bmp_only_with_a_dib_header() {
+    let mut bmp = b"BM\x36\x00\x0c\x00\x00\x00\x00\x00\x36\x00\x00\x00\x28\x00\x00\x00".to_vec();
+    bmp.extend_from_slice(&[0; 36]);
+    assert_eq!(sniff(&bmp), "image/bmp");
+    assert_eq!(sniff(b"BMW drivers meeting notes\n"), "text/plain");
+    assert_eq!(sniff(b"BM"), "text/plain");
+    assert_eq!(sniff(&bmp[..16]), OCTET_STREAM);
+  }
+}
@@ -0,0 +1,343 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use super::error::ServerError;
+use super::{CRLF, HEADER_END};
+
+/// RFC 2046 limits boundaries to 70 characters.
+const MAX_BOUNDARY: usize = 70;
+/// The headers of a part have to fit into the lookahead window at once.
+const MAX_PART_HEADERS: usize = 8 * 1024;
+
+/// The `boundary` parameter of a `multipart/form-data` Content-Type.
+pub fn boundary(content_type: &str) -> Result<String, ServerError> {
+  let (media_type, parameters) = parameters(content_type);
+  if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+    return Err(ServerError::BadRequest(format!("Content-Type {media_type} is not multipart/form-data")))
+  }
+  match parameters.into_iter().find(|(name, _)| name == "boundary") {
+    Some((_, boundary)) if (1..=MAX_BOUNDARY).contains(&boundary.len()) => Ok(boundary),
+    Some((_, boundary)) => Err(ServerError::BadRequest(format!("Invalid multipart boundary `{boundary}`"))),
+    None                => Err(ServerError::BadRequest("Content-Type has no multipart boundary".to_string()))
+  }
+}
+
+/// Splits a header value like `form-data; name="a; b"; filename=c` into its main value and its parameters.
+/// Parameter names are lowercased; quoted values may contain `;` and escape `"` and `\` with a backslash.
+fn parameters(value: &str) -> (String, Vec<(String, String)>) {
+  let mut chars = value.chars().peekable();
+  let mut main = String::new();
+  while let Some(c) = chars.next_if(|c| *c != ';') { main.push(c) }
+
+  let mut parameters = Vec::new();
+  while chars.next().is_some() {
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| *c != '=' && *c != ';') { name.push(c) }
+    let mut value = String::new();
+    if chars.next_if_eq(&'=').is_some() {
+      if chars.next_if_eq(&'"').is_some() {
+        while let Some(c) = chars.next() {
+          match c {
+            '"'  => break,
+            '\\' => value.push(chars.next_if(|c| *c == '"' || *c == '\\').unwrap_or('\\')),
+            c    => value.push(c)
+          }
+        }
+        while chars.next_if(|c| *c != ';').is_some() {}
+      } else {
+        while let Some(c) = chars.next_if(|c| *c != ';') { value.push(c) }
+        value.truncate(value.trim_end().len());
+      }
+    }
+    parameters.push((name.trim().to_ascii_lowercase(), value));
+  }
+  (main.trim().to_string(), parameters)
+}
+
+fn malformed(message: &str) -> ServerError {
+  ServerError::HTTPParseError(format!("Content malformed; {message}"))
+}
+
+/// A part of a form as announced by its headers (RFC 7578). Parts with a file name are file fields.
+pub struct Part {
+  pub name    : String,
+  pub filename: Option<String>
+}
+
+#[derive(PartialEq, Eq)]
+enum State { Preamble, Headers, Body, Done }
+
+/// An incremental `multipart/form-data` parser. `next_part` reads the headers of the next part, whose body is then
+/// streamed by `read_body` or `read_text`; a body that is not read is skipped. Memory use is bounded by a fixed
+/// lookahead window, which keeps just enough bytes to recognize a boundary split across reads.
+pub struct Multipart<R: Read> {
+  reader   : R,
+  /// `CRLF--boundary`, which ends the preamble and every part.
+  delimiter: Vec<u8>,
+  window   : Vec<u8>,
+  start    : usize,
+  end      : usize,
+  state    : State
+}
+
+impl<R: Read> Multipart<R> {
+  pub fn new(reader: R, boundary: &str, buffer_size: usize) -> Self {
+    let delimiter = [&CRLF, b"--", boundary.as_bytes()].concat();
+    let mut window = vec![0; buffer_size.max(MAX_PART_HEADERS) + delimiter.len()];
+    // The first boundary may start the body right away, without the CRLF that precedes it otherwise
+    window[..CRLF.len()].copy_from_slice(&CRLF);
+    Self { reader, delimiter, window, start: 0, end: CRLF.len(), state: State::Preamble }
+  }
+
+  fn buffered(&self) -> &[u8] {
+    &self.window[self.start..self.end]
+  }
+
+  /// Moves the buffered bytes to the front of the window and reads more behind them. Returns false at the end of input.
+  fn fill(&mut self) -> Result<bool, ServerError> {
+    self.window.copy_within(self.start..self.end, 0);
+    self.end -= self.start;
+    self.start = 0;
+    loop {
+      match self.reader.read(&mut self.window[self.end..]) {
+        Ok(read)                                     => { self.end += read; return Ok(read > 0) },
+        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+        Err(e)                                       => return Err(e.into())
+      }
+    }
+  }
+
+  /// Reads until at least `count` bytes are buffered. Returns false if the input ends before.
+  fn ensure(&mut self, count: usize) -> Result<bool, ServerError> {
+    while self.end - self.start < count {
+      if !self.fill()? { return Ok(false) }
+    }
+    Ok(true)
+  }
+
+  /// Writes everything up to the next delimiter to `sink` and consumes the delimiter. Fails with
+  /// `413 Payload Too Large` once more than `limit` bytes would be written.
+  fn copy_to_delimiter<W: Write>(&mut self, sink: &mut W, limit: u64) -> Result<u64, ServerError> {
+    let mut copied = 0;
+    loop {
+      let buffered = self.buffered();
+      let found = find(buffered, &self.delimiter);
+      // Without a delimiter, the last bytes might still be the start of one
+      let safe = found.unwrap_or_else(|| buffered.len().saturating_sub(self.delimiter.len() - 1));
+      copied += safe as u64;
+      if copied > limit { return Err(ServerError::PayloadTooLarge(format!("Form field is larger than {limit} bytes"))) }
+      sink.write_all(&buffered[..safe])?;
+      self.start += safe;
+
+      if found.is_some() {
+        self.start += self.delimiter.len();
+        return Ok(copied)
+      }
+      if !self.fill()? { return Err(malformed("closing boundary not found")) }
+    }
+  }
+
+  /// Reads what follows a delimiter: `--` after the last part, otherwise optional whitespace and a CRLF.
+  fn after_delimiter(&mut self) -> Result<(), ServerError> {
+    if !self.ensure(2)? { return Err(malformed("input ends after a boundary")) }
+    if self.buffered().starts_with(b"--") {
+      self.start += 2;
+      self.state = State::Done;
+      return Ok(())
+    }
+    loop {
+      match self.buffered().first() {
+        Some(b' ' | b'\t') => self.start += 1,
+        Some(_)            => break,
+        None               => if !self.fill()? { return Err(malformed("input ends after a boundary")) }
+      }
+    }
+    if !self.ensure(CRLF.len())? || !self.buffered().starts_with(&CRLF) {
+      return Err(malformed("boundary is not followed by a line break"))
+    }
+    self.start += CRLF.len();
+    self.state = State::Headers;
+    Ok(())
+  }
+
+  /// Skips the rest of the current part (or the preamble) and reads the headers of the next part.
+  /// Returns `None` after the last part.
+  pub fn next_part(&mut self) -> Result<Option<Part>, ServerError> {
+    while self.state != State::Headers {
+      if self.state == State::Done { return Ok(None) }
+      self.copy_to_delimiter(&mut io::sink(), u64::MAX)?;
+      self.after_delimiter()?;
+    }
+
+    // A part without headers starts with the empty line right away
+    let length = loop {
+      if self.buffered().starts_with(&CRLF) { break 0 }
+      if let Some(length) = find(self.buffered(), &HEADER_END) { break length + CRLF.len() }
+      if self.end - self.start > MAX_PART_HEADERS { return Err(malformed("part headers are too large")) }
+      if !self.fill()? { return Err(malformed("part headers are incomplete")) }
+    };
+    let headers = String::from_utf8_lossy(&self.buffered()[..length]).to_string();
+    self.start += length + CRLF.len();
+    self.state = State::Body;
+
+    let disposition = headers.split("\r\n")
+      .filter_map(|line| line.split_once(':'))
+      .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Disposition"))
+      .map(|(_, value)| parameters(value))
+      .ok_or_else(|| malformed("part has no Content-Disposition"))?;
+    let (kind, mut parameters) = disposition;
+    let mut parameter = |name: &str| parameters.iter().position(|(key, _)| key == name).map(|i| parameters.swap_remove(i).1);
+    match (kind.eq_ignore_ascii_case("form-data"), parameter("name")) {
+      (true, Some(name)) => {
+        let filename = parameter("filename");
+        println!("Receiving form field {name}{}", filename.as_ref().map(|filename| format!(" with file {filename}")).unwrap_or_default());
+        Ok(Some(Part { name, filename }))
+      },
+      _                  => Err(malformed("part is not a named form-data field"))
+    }
+  }
+
+  /// Streams the body of the current part to `sink`. Returns its size.
+  pub fn read_body<W: Write>(&mut self, sink: &mut W) -> Result<u64, ServerError> {
+    self.read_limited(sink, u64::MAX)
+  }
+
+  /// The body of the current part as text of at most `limit` bytes.
+  pub fn read_text(&mut self, limit: usize) -> Result<String, ServerError> {
+    let mut text = Vec::new();
+    self.read_limited(&mut text, limit as u64)?;
+    Ok(String::from_utf8(text)?)
+  }
+
+  fn read_limited<W: Write>(&mut self, sink: &mut W, limit: u64) -> Result<u64, ServerError> {
+    if self.state != State::Body { return Err(ServerError::Internal("No multipart body to read".to_string())) }
+    let size = self.copy_to_delimiter(sink, limit)?;
+    self.after_delimiter()?;
+    Ok(size)
+  }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::MAX_FIELD_SIZE;
+
+  const BOUNDARY: &str = "----form7MA4YWxk";
+
+  /// Hands out one byte per read, so every boundary is split across reads.
+  struct Trickle<'a>(&'a [u8]);
+
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let count = buf.len().min(self.0.len()).min(1);
+      buf[..count].copy_from_slice(&self.0[..count]);
+      self.0 = &self.0[count..];
+      Ok(count)
+    }
+  }
+
+  fn field(name: &str, filename: Option<&str>, body: &[u8]) -> Vec<u8> {
+    let filename = filename.map(|filename| format!("; filename=\"{filename}\"")).unwrap_or_default();
+    let headers = format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"{filename}\r\nContent-Type: text/plain\r\n\r\n");
+    [headers.as_bytes(), body, b"\r\n"].concat()
+  }
+
+  fn form(fields: &[Vec<u8>]) -> Vec<u8> {
+    [fields.concat(), format!("--{BOUNDARY}--\r\n").into_bytes()].concat()
+  }
+
+  /// A part as `(name, filename, body)`.
+  type Parsed = (String, Option<String>, Vec<u8>);
+
+  fn parse<R: Read>(reader: R) -> Result<Vec<Parsed>, ServerError> {
+    let mut form = Multipart::new(reader, BOUNDARY, 16);
+    let mut parts = Vec::new();
+    while let Some(part) = form.next_part()? {
+      let mut body = Vec::new();
+      form.read_body(&mut body)?;
+      parts.push((part.name, part.filename, body));
+    }
+    Ok(parts)
+  }
+
+  fn expected() -> (Vec<u8>, Vec<Parsed>) {
+    // Bodies that contain line breaks and prefixes of the delimiter must come through unchanged
+    let tricky = format!("line\r\n--{}\r\n-\r\n--", &BOUNDARY[..BOUNDARY.len() - 1]).into_bytes();
+    let body = form(&[field("note", None, b"hello"), field("file", Some("a.txt"), &tricky), field("empty", Some("b.txt"), b"")]);
+    let parts = vec![
+      ("note".to_string(), None, b"hello".to_vec()),
+      ("file".to_string(), Some("a.txt".to_string()), tricky),
+      ("empty".to_string(), Some("b.txt".to_string()), Vec::new())
+    ];
+    (body, parts)
+  }
+
+  #[test]
+  fn parses_parts() {
+    let (body, parts) = expected();
+    assert_eq!(parse(body.as_slice()).unwrap(), parts);
+  }
+
+  #[test]
+  fn finds_boundaries_split_across_reads() {
+    let (body, parts) = expected();
+    assert_eq!(parse(Trickle(&body)).unwrap(), parts);
+  }
+
+  #[test]
+  fn skips_preamble_and_epilogue() {
+    let (body, parts) = expected();
+    let body = [b"This is the preamble.\r\n".as_slice(), &body, b"This is the epilogue.\r\n--ignored--"].concat();
+    assert_eq!(parse(body.as_slice()).unwrap(), parts);
+    assert_eq!(parse(Trickle(&body)).unwrap(), parts);
+  }
+
+  #[test]
+  fn skips_unread_bodies() {
+    let (body, _) = expected();
+    let mut form = Multipart::new(body.as_slice(), BOUNDARY, 16);
+    let mut names = Vec::new();
+    while let Some(part) = form.next_part().unwrap() { names.push(part.name) }
+    assert_eq!(names, ["note", "file", "empty"]);
+  }
+
+  #[test]
+  fn rejects_part_without_headers() {
+    let body = format!("--{BOUNDARY}\r\n\r\nbody\r\n--{BOUNDARY}--\r\n");
+    assert!(matches!(parse(body.as_bytes()), Err(ServerError::HTTPParseError(_))));
+  }
+
+  #[test]
+  fn rejects_missing_closing_boundary() {
+    let body = field("file", Some("a.txt"), b"content");
+    assert!(matches!(parse(body.as_slice()), Err(ServerError::HTTPParseError(_))));
+    let body = form(&[field("file", Some("a.txt"), b"content")]);
+    let body = &body[..body.len() - "--\r\n".len()];
+    assert!(matches!(parse(body), Err(ServerError::HTTPParseError(_))));
+  }
+
+  #[test]
+  fn limits_text_fields() {
+    for (size, fits) in [(MAX_FIELD_SIZE, true), (MAX_FIELD_SIZE + 1, false)] {
+      let body = form(&[field("note", None, &vec![b'a'; size])]);
+      let mut form = Multipart::new(body.as_slice(), BOUNDARY, 16);
+      assert!(form.next_part().unwrap().is_some());
+      match form.read_text(MAX_FIELD_SIZE) {
+        Ok(text)                             => assert!(fits && text.len() == size),
+        Err(ServerError::PayloadTooLarge(_)) => assert!(!fits),
+        Err(e)                               => panic!("unexpected error {e}")
+      }
+    }
+  }
+
+  #[test]
+  fn reads_boundary_parameter() {
+    assert_eq!(boundary(&format!("multipart/form-data; charset=utf-8; boundary=\"{BOUNDARY}\"")).unwrap(), BOUNDARY);
+    assert!(boundary("multipart/form-data").is_err());
+    assert!(boundary(&format!("multipart/form-data; boundary={}", "x".repeat(MAX_BOUNDARY + 1))).is_err());
+    assert!(boundary("text/plain; boundary=x").is_err());
+  }
+}
@@ -0,0 +1,335 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+
+use super::error::ServerError;
+use super::trash::TRASH_DIR;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymlinkPolicy { FollowInsideRoot, FollowAny, Deny }
+
+impl TryFrom<&str> for SymlinkPolicy {
+  type Error = ServerError;
+
+  fn try_from(value: &str) -> Result<Self, Self::Error> {
+    match value {
+      "follow_inside_root" | "inside_root" => Ok(SymlinkPolicy::FollowInsideRoot),
+      "follow_any"         | "any"         => Ok(SymlinkPolicy::FollowAny),
+      "deny"                               => Ok(SymlinkPolicy::Deny),
+      _ => Err(ServerError::ConfigError(format!("Invalid symlink policy `{value}` (expected inside_root, any or deny)")))
+    }
+  }
+}
+
+/// Which characters names of newly created entries may contain.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NamePolicy {
+  /// Anything the filesystem accepts
+  Any,
+  /// No control characters
+  Printable,
+  /// Only the POSIX portable filename characters: letters, digits, `.`, `_` and `-`
+  Portable,
+  /// Printable and also valid on Windows, e.g. for trees shared over SMB
+  Windows
+}
+
+impl TryFrom<&str> for NamePolicy {
+  type Error = ServerError;
+
+  fn try_from(value: &str) -> Result<Self, Self::Error> {
+    match value {
+      "any"       => Ok(NamePolicy::Any),
+      "printable" => Ok(NamePolicy::Printable),
+      "portable"  => Ok(NamePolicy::Portable),
+      "windows"   => Ok(NamePolicy::Windows),
+      _ => Err(ServerError::ConfigError(format!("Invalid name policy `{value}` (expected any, printable, portable or windows)")))
+    }
+  }
+}
+
+const WINDOWS_RESERVED: [&str; 22] = [
+  "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+  "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+/// Checks a single component of a path about to be created against `policy`.
+pub fn check_name(name: &OsStr, policy: NamePolicy) -> Result<(), ServerError> {
+  let invalid = |reason: &str| Err(ServerError::BadRequest(format!("Invalid name `{}`: {reason}", name.to_string_lossy())));
+  let bytes = name.as_bytes();
+  if bytes.is_empty() || bytes == b"." || bytes == b".." { return invalid("reserved") }
+  if bytes.contains(&b'/') || bytes.contains(&0) { return invalid("contains `/` or NUL") }
+  if policy == NamePolicy::Any { return Ok(()) }
+
+  let Some(text) = name.to_str() else { return invalid("not valid UTF-8") };
+  if text.chars().any(char::is_control) { return invalid("contains control characters") }
+  match policy {
+    NamePolicy::Portable if !bytes.iter().all(|b| b.is_ascii_alphanumeric() || b"._-".contains(b)) =>
+      invalid("only letters, digits, `.`, `_` and `-` are allowed"),
+    NamePolicy::Windows if text.contains(['<', '>', ':', '"', '\\', '|', '?', '*']) =>
+      invalid("contains one of < > : \" \\ | ? *"),
+    NamePolicy::Windows if text.ends_with(['.', ' ']) => invalid("ends with `.` or a space"),
+    NamePolicy::Windows if WINDOWS_RESERVED.iter().any(|reserved| text.split('.').next().unwrap_or_default().eq_ignore_ascii_case(reserved)) =>
+      invalid("reserved on Windows"),
+    _ => Ok(())
+  }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+  match c {
+    b'0'..=b'9' => Some(c - b'0'),
+    b'a'..=b'f' => Some(c - b'a' + 10),
+    b'A'..=b'F' => Some(c - b'A' + 10),
+    _           => None
+  }
+}
+
+/// Percent-decodes `s` byte-wise, so multi-byte UTF-8 sequences and non-UTF-8 names survive.
+/// Malformed escapes are kept as they are.
+pub fn decode_url(s: &str) -> OsString {
+  let encoded = s.as_bytes();
+  let mut decoded = Vec::with_capacity(encoded.len());
+  let mut i = 0;
+
+  while i < encoded.len() {
+    match (encoded[i], encoded.get(i+1).and_then(|c| hex_value(*c)), encoded.get(i+2).and_then(|c| hex_value(*c))) {
+      (b'%', Some(high), Some(low)) => { decoded.push(high << 4 | low); i += 3; },
+      (c, _, _)                     => { decoded.push(c); i += 1; }
+    }
+  }
+
+  OsString::from_vec(decoded)
+}
+
+/// Percent-encodes a single path segment, the inverse of `decode_url`. Only unreserved characters
+/// (RFC 3986, 2.3) are kept, so the result is safe in any URL, HTML attribute or JS string context.
+pub fn encode_url(segment: &OsStr) -> String {
+  segment.as_bytes().iter().fold(String::with_capacity(segment.len()), |mut acc, byte| {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => acc.push(*byte as char),
+      _                                                                   => acc.push_str(&format!("%{byte:02X}"))
+    }
+    acc
+  })
+}
+
+/// The URL of `relative`, a path below the root: normalized, percent-encoded and with exactly one leading `/`,
+/// so it can't be mistaken for a protocol-relative URL.
+pub fn encode_url_path(relative: &Path) -> Result<String, ServerError> {
+  Ok(normalize(relative)?.iter().fold(String::from("/"), |mut acc, segment| {
+    if acc.len() > 1 { acc.push('/') }
+    acc.push_str(&encode_url(segment));
+    acc
+  }))
+}
+
+/// Strips query and fragment from a request target and percent-decodes the remaining path.
+pub fn url_path(url: &str) -> Result<PathBuf, ServerError> {
+  let raw = url.split(['?', '#']).next().unwrap_or_default();
+  let decoded = decode_url(raw);
+  if decoded.as_encoded_bytes().contains(&0) {
+    return Err(ServerError::BadRequest(format!("Path {raw} contains a NUL byte")))
+  }
+  Ok(PathBuf::from(decoded))
+}
+
+/// Ensures `name` is a single, plain path component (no separators, `.` or `..`).
+pub fn file_name(name: &str) -> Result<&Path, ServerError> {
+  let path = Path::new(name);
+  let mut components = path.components();
+  match (components.next(), components.next()) {
+    (Some(Component::Normal(_)), None) if !name.contains('/') && !name.contains('\0') => Ok(path),
+    _ => Err(ServerError::BadRequest(format!("Invalid file name `{name}`")))
+  }
+}
+
+fn normalize(relative: &Path) -> Result<PathBuf, ServerError> {
+  let mut normalized = PathBuf::new();
+  for component in relative.components() {
+    match component {
+      Component::Normal(name)                                       => normalized.push(name),
+      Component::CurDir | Component::RootDir | Component::Prefix(_) => (),
+      Component::ParentDir => if !normalized.pop() {
+        return Err(ServerError::Forbidden(format!("Path {} escapes the root", relative.display())))
+      }
+    }
+  }
+  Ok(normalized)
+}
+
+/// Normalizes `relative` (dropping `.`, empty and root segments and applying `..`) and resolves it against `root`.
+/// Existing components are checked against the symlink policy, so the result is guaranteed to stay inside `root`
+/// unless the policy is `FollowAny`. Components that do not exist yet are appended as they are.
+pub fn resolve(root: &Path, relative: &Path, policy: SymlinkPolicy) -> Result<PathBuf, ServerError> {
+  let normalized = normalize(relative)?;
+  let root = fs::canonicalize(root)?;
+  let mut resolved = root.clone();
+  let mut exists = true;
+
+  for component in normalized.components() {
+    let candidate = resolved.join(component);
+    if !exists { resolved = candidate; continue }
+
+    match fs::symlink_metadata(&candidate) {
+      Ok(md) if md.file_type().is_symlink() => {
+        let target = match policy {
+          SymlinkPolicy::Deny => return Err(ServerError::Forbidden(format!("Symlink {} is not followed", candidate.display()))),
+          _                   => fs::canonicalize(&candidate)?
+        };
+        if policy == SymlinkPolicy::FollowInsideRoot && !target.starts_with(&root) {
+          return Err(ServerError::Forbidden(format!("Symlink {} points outside the root", candidate.display())))
+        }
+        resolved = target;
+      },
+      Ok(_)                                    => resolved = candidate,
+      Err(e) if e.kind() == ErrorKind::NotFound => { exists = false; resolved = candidate },
+      Err(e)                                   => return Err(e.into())
+    }
+  }
+
+  check_trash(&root, &resolved, relative)?;
+  Ok(resolved)
+}
+
+/// The recycle bin is only reachable through its own handler, so paths at or below it are reported as missing.
+fn check_trash(root: &Path, resolved: &Path, relative: &Path) -> Result<(), ServerError> {
+  match resolved.strip_prefix(root).ok().and_then(|inside| inside.components().next()) {
+    Some(Component::Normal(first)) if first == OsStr::new(TRASH_DIR) =>
+      Err(ServerError::NotFound(format!("Path {} does not exist", relative.display()))),
+    _ => Ok(())
+  }
+}
+
+/// Resolves the path part of a request target against `root`.
+pub fn resolve_url(root: &Path, url: &str, policy: SymlinkPolicy) -> Result<PathBuf, ServerError> {
+  resolve(root, &url_path(url)?, policy)
+}
+
+/// Like `resolve`, but a symlink in the last component is not followed, so the result names the entry itself.
+/// That's what deleting, moving, renaming or replacing has to act on.
+pub fn resolve_entry(root: &Path, relative: &Path, policy: SymlinkPolicy) -> Result<PathBuf, ServerError> {
+  let normalized = normalize(relative)?;
+  let root = fs::canonicalize(root)?;
+  let resolved = match (normalized.parent(), normalized.file_name()) {
+    (Some(parent), Some(name)) => resolve(&root, parent, policy)?.join(name),
+    _                          => return Ok(root)
+  };
+  check_trash(&root, &resolved, &normalized)?;
+  Ok(resolved)
+}
+
+/// Resolves the path part of a request target like `resolve_entry`.
+pub fn resolve_entry_url(root: &Path, url: &str, policy: SymlinkPolicy) -> Result<PathBuf, ServerError> {
+  resolve_entry(root, &url_path(url)?, policy)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::symlink;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  /// A root with a file, a recycle bin and symlinks to inside and outside of it, removed when dropped.
+  /// ```text
+  /// root/sub/file  root/.trash/old  root/in -> sub  root/up -> sub/..  root/bin -> .trash  root/out -> ../outside
+  /// outside/secret
+  /// ```
+  struct Fixture { base: PathBuf, root: PathBuf }
+
+  impl Fixture {
+    fn new() -> Self {
+      static COUNT: AtomicUsize = AtomicUsize::new(0);
+      let base = std::env::temp_dir().join(format!("fileserve-path-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+      let root = base.join("root");
+      for directory in [root.join("sub"), root.join(TRASH_DIR), base.join("outside")] { fs::create_dir_all(directory).unwrap() }
+      for file in [root.join("sub/file"), root.join(TRASH_DIR).join("old"), base.join("outside/secret")] { fs::write(file, b"").unwrap() }
+      for (link, target) in [("in", "sub"), ("up", "sub/.."), ("bin", TRASH_DIR), ("out", "../outside")] {
+        symlink(target, root.join(link)).unwrap();
+      }
+      let root = fs::canonicalize(root).unwrap();
+      Self { base: fs::canonicalize(base).unwrap(), root }
+    }
+
+    fn resolve(&self, url: &str, policy: SymlinkPolicy) -> Result<PathBuf, ServerError> {
+      resolve_url(&self.root, url, policy)
+    }
+  }
+
+  impl Drop for Fixture {
+    fn drop(&mut self) { let _ = fs::remove_dir_all(&self.base); }
+  }
+
+  const POLICIES: [SymlinkPolicy; 3] = [SymlinkPolicy::FollowInsideRoot, SymlinkPolicy::FollowAny, SymlinkPolicy::Deny];
+
+  #[test]
+  fn resolves_plain_and_missing_paths() {
+    let fixture = Fixture::new();
+    for policy in POLICIES {
+      assert_eq!(fixture.resolve("/", policy).unwrap(), fixture.root);
+      assert_eq!(fixture.resolve("/sub/file?download#top", policy).unwrap(), fixture.root.join("sub/file"));
+      assert_eq!(fixture.resolve("//sub/./x/../file", policy).unwrap(), fixture.root.join("sub/file"));
+      assert_eq!(fixture.resolve("/new/deeper", policy).unwrap(), fixture.root.join("new/deeper"));
+    }
+  }
+
+  #[test]
+  fn refuses_to_leave_the_root() {
+    let fixture = Fixture::new();
+    for url in ["/..", "/../outside/secret", "/sub/../../outside", "/%2e%2e/outside", "/sub/%2E%2E/%2e%2e", "/%2e%2e%2foutside"] {
+      for policy in POLICIES {
+        assert!(matches!(fixture.resolve(url, policy), Err(ServerError::Forbidden(_))), "{url} with {policy:?}");
+      }
+    }
+    assert_eq!(fixture.resolve("/sub/%2e%2e/sub/file", SymlinkPolicy::Deny).unwrap(), fixture.root.join("sub/file"));
+  }
+
+  #[test]
+  fn follows_symlinks_by_policy() {
+    let fixture = Fixture::new();
+    let inside = fixture.root.join("sub/file");
+    let outside = fixture.base.join("outside/secret");
+    let forbidden = |result: Result<PathBuf, ServerError>| matches!(result, Err(ServerError::Forbidden(_)));
+
+    assert_eq!(fixture.resolve("/in/file", SymlinkPolicy::FollowInsideRoot).unwrap(), inside);
+    assert_eq!(fixture.resolve("/up/sub/file", SymlinkPolicy::FollowInsideRoot).unwrap(), inside);
+    assert!(forbidden(fixture.resolve("/out/secret", SymlinkPolicy::FollowInsideRoot)));
+
+    assert_eq!(fixture.resolve("/in/file", SymlinkPolicy::FollowAny).unwrap(), inside);
+    assert_eq!(fixture.resolve("/out/secret", SymlinkPolicy::FollowAny).unwrap(), outside);
+
+    for url in ["/in/file", "/up/sub/file", "/out/secret", "/out"] {
+      assert!(forbidden(fixture.resolve(url, SymlinkPolicy::Deny)), "{url}");
+    }
+  }
+
+  #[test]
+  fn resolves_entries_without_following_the_last_symlink() {
+    let fixture = Fixture::new();
+    for policy in [SymlinkPolicy::FollowInsideRoot, SymlinkPolicy::Deny] {
+      assert_eq!(resolve_entry_url(&fixture.root, "/out", policy).unwrap(), fixture.root.join("out"));
+    }
+    assert!(matches!(resolve_entry_url(&fixture.root, "/out/secret", SymlinkPolicy::FollowInsideRoot), Err(ServerError::Forbidden(_))));
+    assert_eq!(resolve_entry_url(&fixture.root, "/", SymlinkPolicy::Deny).unwrap(), fixture.root);
+  }
+
+  #[test]
+  fn encodes_url_paths_with_one_leading_slash() {
+    for (relative, url) in [("", "/"), ("/", "/"), ("//evil.example", "/evil.example"), ("a b/./c//d", "/a%20b/c/d"), ("a/../ü", "/%C3%BC")] {
+      assert_eq!(encode_url_path(Path::new(relative)).unwrap(), url, "{relative}");
+    }
+    assert!(encode_url_path(Path::new("../x")).is_err());
+  }
+
+  #[test]
+  fn hides_the_trash() {
+    let fixture = Fixture::new();
+    for url in ["/.trash", "/.trash/old", "/%2etrash/old", "/sub/../.trash", "/in/%2e%2e/.trash/old", "/bin/old"] {
+      assert!(matches!(fixture.resolve(url, SymlinkPolicy::FollowAny), Err(ServerError::NotFound(_))), "{url}");
+      assert!(matches!(resolve_entry_url(&fixture.root, url, SymlinkPolicy::FollowAny), Err(ServerError::NotFound(_))), "{url}");
+    }
+    assert!(matches!(fixture.resolve("/bin", SymlinkPolicy::FollowInsideRoot), Err(ServerError::NotFound(_))));
+    assert_eq!(fixture.resolve("/sub/.trash", SymlinkPolicy::Deny).unwrap(), fixture.root.join("sub/.trash"));
+  }
+}
@@ -0,0 +1,188 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::cache::Validators;
+use super::date;
+use super::response::Body;
+
+const MAX_RANGES: usize = 16;
+
+/// An inclusive range of byte positions.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ByteRange {
+  pub start: u64,
+  pub end  : u64
+}
+
+impl ByteRange {
+  pub fn len(&self) -> u64 { self.end - self.start + 1 }
+
+  pub fn content_range(&self, total: u64) -> String {
+    format!("bytes {}-{}/{total}", self.start, self.end)
+  }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Ranges {
+  Full,
+  Partial(Vec<ByteRange>),
+  Unsatisfiable
+}
+
+/// Interprets a `Range` header for a file of `length` bytes.
+/// Headers that are malformed, use another unit than bytes or ask for too many ranges are ignored (RFC 9110, 14.2).
+/// Overlapping and adjacent ranges are coalesced, so no byte is sent twice.
+pub fn parse(value: &str, length: u64) -> Ranges {
+  let specs = match value.trim().strip_prefix("bytes=") {
+    Some(specs) => specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect::<Vec<&str>>(),
+    None        => return Ranges::Full
+  };
+  if specs.is_empty() || specs.len() > MAX_RANGES { return Ranges::Full }
+
+  let mut ranges = Vec::new();
+  for spec in specs {
+    let (first, last) = match spec.split_once('-') {
+      Some(bounds) => bounds,
+      None         => return Ranges::Full
+    };
+    let range = match (position(first), position(last)) {
+      (Ok(first), Ok(last)) if first <= last   => (first < length).then(|| ByteRange { start: first, end: last.min(length-1) }),
+      (Ok(first), Err(_))   if last.is_empty()  => (first < length).then(|| ByteRange { start: first, end: length-1 }),
+      (Err(_), Ok(suffix))  if first.is_empty() => (suffix > 0 && length > 0).then(|| ByteRange { start: length - suffix.min(length), end: length-1 }),
+      _                                         => return Ranges::Full
+    };
+    ranges.extend(range);
+  }
+
+  if ranges.is_empty() { return Ranges::Unsatisfiable }
+  ranges.sort_by_key(|range| range.start);
+  let coalesced = ranges.into_iter().fold(Vec::<ByteRange>::new(), |mut acc, range| {
+    match acc.last_mut() {
+      Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+      _                                                       => acc.push(range)
+    }
+    acc
+  });
+  Ranges::Partial(coalesced)
+}
+
+/// A byte position, which unlike `u64::from_str` takes no sign.
+fn position(value: &str) -> Result<u64, ()> {
+  match !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+    true  => value.parse().map_err(|_| ()),
+    false => Err(())
+  }
+}
+
+/// Whether an `If-Range` validator (a strong entity tag or a date) still matches the file,
+/// i.e. whether the requested ranges may be served instead of the whole file.
+pub fn if_range_matches(value: &str, validators: &Validators) -> bool {
+  if value.trim().starts_with('"') {
+    value.trim() == validators.etag
+  } else {
+    match (date::parse(value), validators.last_modified) {
+      (Some(since), Some(modified)) => since == date::truncate(modified),
+      _                             => false
+    }
+  }
+}
+
+/// A separator that is unlikely to show up in the file contents.
+pub fn boundary() -> String {
+  let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+  format!("fileserve-{nanos:032x}")
+}
+
+/// Streams a `multipart/byteranges` body holding each of the `ranges`.
+pub fn multipart(mut file: File, ranges: Vec<ByteRange>, total: u64, content_type: &str, boundary: &str) -> Body {
+  let part_heads = ranges
+    .iter()
+    .map(|range| format!("--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n", range.content_range(total)))
+    .collect::<Vec<String>>();
+  let closing = format!("--{boundary}--\r\n");
+  let length  = part_heads.iter().zip(&ranges).map(|(head, range)| head.len() as u64 + range.len() + 2).sum::<u64>() + closing.len() as u64;
+
+  Body::Generator {
+    length  : Some(length),
+    generate: Box::new(move |out: &mut dyn Write| {
+      for (head, range) in part_heads.iter().zip(ranges) {
+        out.write_all(head.as_bytes())?;
+        file.seek(SeekFrom::Start(range.start))?;
+        io::copy(&mut (&mut file).take(range.len()), out)?;
+        out.write_all(b"\r\n")?;
+      }
+      out.write_all(closing.as_bytes())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  fn partial(ranges: &[(u64, u64)]) -> Ranges {
+    Ranges::Partial(ranges.iter().map(|&(start, end)| ByteRange { start, end }).collect())
+  }
+
+  #[test]
+  fn parses_single_ranges() {
+    assert_eq!(parse("bytes=0-0", 100), partial(&[(0, 0)]));
+    assert_eq!(parse("bytes=10-19", 100), partial(&[(10, 19)]));
+    assert_eq!(parse(" bytes=90-1000 ", 100), partial(&[(90, 99)]));
+    // Open-ended
+    assert_eq!(parse("bytes=95-", 100), partial(&[(95, 99)]));
+    // Suffix
+    assert_eq!(parse("bytes=-10", 100), partial(&[(90, 99)]));
+    assert_eq!(parse("bytes=-1000", 100), partial(&[(0, 99)]));
+  }
+
+  #[test]
+  fn parses_and_coalesces_multiple_ranges() {
+    assert_eq!(parse("bytes=0-9, 20-29", 100), partial(&[(0, 9), (20, 29)]));
+    assert_eq!(parse("bytes=50-59,0-9,", 100), partial(&[(0, 9), (50, 59)]));
+    assert_eq!(parse("bytes=0-9,5-14,-95", 100), partial(&[(0, 99)]));
+    assert_eq!(parse("bytes=0-9,10-19", 100), partial(&[(0, 19)]));
+    assert_eq!(parse("bytes=0-0,0-0,0-0", 100), partial(&[(0, 0)]));
+    // Unsatisfiable ranges are left out as long as one remains
+    assert_eq!(parse("bytes=200-300,0-1", 100), partial(&[(0, 1)]));
+  }
+
+  #[test]
+  fn reports_unsatisfiable_ranges() {
+    assert_eq!(parse("bytes=100-", 100), Ranges::Unsatisfiable);
+    assert_eq!(parse("bytes=100-200,300-", 100), Ranges::Unsatisfiable);
+    assert_eq!(parse("bytes=-0", 100), Ranges::Unsatisfiable);
+    // Nothing of an empty file can be selected
+    for value in ["bytes=0-0", "bytes=0-", "bytes=-1"] {
+      assert_eq!(parse(value, 0), Ranges::Unsatisfiable, "{value}");
+    }
+  }
+
+  #[test]
+  fn ignores_malformed_headers() {
+    let too_many = format!("bytes={}", (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 10, i * 10 + 1)).collect::<Vec<String>>().join(","));
+    for value in [
+      "", "bytes=", "bytes=,", "items=0-1", "bytes 0-1", "bytes=1", "bytes=-", "bytes=5-1", "bytes=a-b", "bytes=0-1,x",
+      "bytes=+1-2", "bytes=1-+2", "bytes=--1", "bytes=0x1-2", "bytes=18446744073709551616-", &too_many
+    ] {
+      assert_eq!(parse(value, 100), Ranges::Full, "{value}");
+    }
+  }
+
+  #[test]
+  fn matches_if_range_validators() {
+    let modified = UNIX_EPOCH + Duration::from_secs(784111777) + Duration::from_millis(500);
+    let validators = Validators { etag: "\"abc\"".to_string(), last_modified: Some(modified) };
+    assert!(if_range_matches("\"abc\"", &validators));
+    assert!(if_range_matches(" Sun, 06 Nov 1994 08:49:37 GMT", &validators));
+    for value in ["\"abd\"", "W/\"abc\"", "abc", "Sun, 06 Nov 1994 08:49:36 GMT", "Sun, 06 Nov 1994 08:49:38 GMT", "yesterday", ""] {
+      assert!(!if_range_matches(value, &validators), "{value}");
+    }
+
+    let weak = Validators { etag: "W/\"abc\"".to_string(), last_modified: None };
+    assert!(!if_range_matches("W/\"abc\"", &weak));
+    assert!(!if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT", &weak));
+  }
+}
@@ -0,0 +1,285 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use super::error::ServerError;
+use super::escape;
+
+/// The size of the chunks bodies of unknown length are sent in.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Status {
+  Ok,
+  Created,
+  PartialContent,
+  MultiStatus,
+  MovedPermanently,
+  NotModified,
+  BadRequest,
+  Forbidden,
+  NotFound,
+  MethodNotAllowed,
+  RequestTimeout,
+  Conflict,
+  PreconditionFailed,
+  PayloadTooLarge,
+  RangeNotSatisfiable,
+  ExpectationFailed,
+  InternalServerError,
+  InsufficientStorage
+}
+
+impl Status {
+  pub fn code(&self) -> u16 {
+    match self {
+      Status::Ok                  => 200,
+      Status::Created             => 201,
+      Status::PartialContent      => 206,
+      Status::MultiStatus         => 207,
+      Status::MovedPermanently    => 301,
+      Status::NotModified         => 304,
+      Status::BadRequest          => 400,
+      Status::Forbidden           => 403,
+      Status::NotFound            => 404,
+      Status::MethodNotAllowed    => 405,
+      Status::RequestTimeout      => 408,
+      Status::Conflict            => 409,
+      Status::PreconditionFailed  => 412,
+      Status::PayloadTooLarge     => 413,
+      Status::RangeNotSatisfiable => 416,
+      Status::ExpectationFailed   => 417,
+      Status::InternalServerError => 500,
+      Status::InsufficientStorage => 507
+    }
+  }
+
+  pub fn reason(&self) -> &'static str {
+    match self {
+      Status::Ok                  => "OK",
+      Status::Created             => "Created",
+      Status::PartialContent      => "Partial Content",
+      Status::MultiStatus         => "Multi-Status",
+      Status::MovedPermanently    => "Moved Permanently",
+      Status::NotModified         => "Not Modified",
+      Status::BadRequest          => "Bad Request",
+      Status::Forbidden           => "Forbidden",
+      Status::NotFound            => "Not Found",
+      Status::MethodNotAllowed    => "Method Not Allowed",
+      Status::RequestTimeout      => "Request Timeout",
+      Status::Conflict            => "Conflict",
+      Status::PreconditionFailed  => "Precondition Failed",
+      Status::PayloadTooLarge     => "Payload Too Large",
+      Status::RangeNotSatisfiable => "Range Not Satisfiable",
+      Status::ExpectationFailed   => "Expectation Failed",
+      Status::InternalServerError => "Internal Server Error",
+      Status::InsufficientStorage => "Insufficient Storage"
+    }
+  }
+}
+
+impl Display for Status {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} {}", self.code(), self.reason())
+  }
+}
+
+pub type Generator = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()>>;
+
+/// What follows the header of a response. Only `Bytes` is held in memory as a whole.
+pub enum Body {
+  Bytes(Vec<u8>),
+  File { file: File, offset: u64, length: u64 },
+  Generator { length: Option<u64>, generate: Generator }
+}
+
+impl Body {
+  /// The number of bytes the body will produce, if known in advance.
+  pub fn len(&self) -> Option<u64> {
+    match self {
+      Body::Bytes(bytes)             => Some(bytes.len() as u64),
+      Body::File { length, .. }      => Some(*length),
+      Body::Generator { length, .. } => *length
+    }
+  }
+}
+
+impl From<Vec<u8>> for Body {
+  fn from(bytes: Vec<u8>) -> Self { Body::Bytes(bytes) }
+}
+
+pub struct Response {
+  pub status : Status,
+  pub headers: Vec<(String, String)>,
+  pub body   : Body
+}
+
+impl Response {
+  pub fn new<B: Into<Body>>(status: Status, body: B) -> Self {
+    Self { status, headers: Vec::new(), body: body.into() }
+  }
+
+  pub fn ok<B: Into<Body>>(body: B) -> Self { Self::new(Status::Ok, body) }
+
+  pub fn text(status: Status, text: &str) -> Self {
+    Self::new(status, text.as_bytes().to_vec()).with_header("Content-Type", "text/plain; charset=utf-8")
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  /// Answers a failed request, as an HTML page if the client accepts HTML (i.e. a browser) and as JSON otherwise.
+  pub fn error(error: &ServerError, accept: Option<&String>) -> Self {
+    let status  = error.status();
+    let message = error.public_message();
+    let response = if accept.is_some_and(|accept| accept.contains("text/html")) {
+      let page = format!(
+        "<!doctype html>\n<html lang=\"en\">\n<head><meta charset=\"UTF-8\" /><title>{status}</title></head>\n<body>\n<h1>{status}</h1>\n<p>{}</p>\n<a href=\"/\">Back to files</a>\n</body>\n</html>",
+        escape::html(&message));
+      Self::new(status, page.into_bytes()).with_header("Content-Type", "text/html; charset=utf-8")
+    } else {
+      let json = format!("{{\"status\":{},\"error\":\"{}\",\"message\":\"{}\"}}", status.code(), status.reason(), escape::json(&message));
+      Self::new(status, json.into_bytes()).with_header("Content-Type", "application/json")
+    };
+
+    match error {
+      ServerError::MethodNotAllowed(_) => response.with_header("Allow", super::ALLOWED_METHODS),
+      _                                => response
+    }
+  }
+
+  /// Writes the header and then the body. File bodies are copied in chunks by `io::copy`, which uses
+  /// `sendfile`/`splice` on Linux when `out` is a socket, so memory use does not depend on the file size.
+  /// Bodies of unknown length are sent with chunked transfer coding if `chunked`, and are delimited by closing
+  /// the connection afterwards otherwise.
+  /// Clients are told not to second-guess the `Content-Type` of any response.
+  pub fn write_to<W: Write>(self, out: &mut W, chunked: bool) -> io::Result<()> {
+    let head = self.headers
+      .iter()
+      .fold(format!("HTTP/1.1 {}\r\nX-Content-Type-Options: nosniff\r\n", self.status), |mut acc, (name, value)| {
+        acc.push_str(&format!("{name}: {value}\r\n"));
+        acc
+      });
+    // A 304 describes the representation the client already has, so it must not claim a length of 0
+    let length = match (self.status, self.body.len()) {
+      (Status::NotModified, _) => String::new(),
+      (_, None) if chunked     => "Transfer-Encoding: chunked\r\n".to_string(),
+      (_, None)                => String::new(),
+      (_, Some(length))        => format!("Content-Length: {length}\r\n")
+    };
+    out.write_all([head.as_bytes(), length.as_bytes(), &super::CRLF].concat().as_slice())?;
+
+    match self.body {
+      Body::Bytes(bytes)                     => out.write_all(&bytes)?,
+      Body::File { mut file, offset, length } => {
+        file.seek(SeekFrom::Start(offset))?;
+        io::copy(&mut file.take(length), out)?;
+      },
+      Body::Generator { generate, length: None } if chunked => {
+        // Buffered, so small writes of the generator don't each become a chunk
+        let mut chunks = BufWriter::with_capacity(CHUNK_SIZE, Chunked(&mut *out));
+        generate(&mut chunks)?;
+        chunks.flush()?;
+        drop(chunks);
+        out.write_all(b"0\r\n\r\n")?;
+      },
+      Body::Generator { generate, .. }       => generate(out)?
+    }
+    out.flush()
+  }
+}
+
+/// Sends everything written to it as one chunk (RFC 9112, 7.1), without the last chunk that ends the body.
+struct Chunked<W: Write>(W);
+
+impl<W: Write> Write for Chunked<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if buf.is_empty() { return Ok(0) }
+    self.0.write_all(format!("{:X}\r\n", buf.len()).as_bytes())?;
+    self.0.write_all(buf)?;
+    self.0.write_all(&super::CRLF)?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn streamed(parts: Vec<Vec<u8>>) -> Response {
+    Response::ok(Body::Generator {
+      length  : None,
+      generate: Box::new(move |out| parts.iter().try_for_each(|part| out.write_all(part)))
+    })
+  }
+
+  fn split_head(written: &[u8]) -> (String, &[u8]) {
+    let end = written.windows(4).position(|window| window == b"\r\n\r\n").expect("no end of header") + 4;
+    (String::from_utf8_lossy(&written[..end]).to_string(), &written[end..])
+  }
+
+  /// Decodes a chunked body, checking its framing. Returns the payload and the sizes of the chunks.
+  fn dechunk(mut body: &[u8]) -> (Vec<u8>, Vec<usize>) {
+    let (mut payload, mut sizes) = (Vec::new(), Vec::new());
+    loop {
+      let line = body.windows(2).position(|window| window == b"\r\n").expect("chunk size without line break");
+      let size = usize::from_str_radix(std::str::from_utf8(&body[..line]).unwrap(), 16).expect("chunk size is not hex");
+      body = &body[line + 2..];
+      if size == 0 {
+        assert_eq!(body, b"\r\n", "last chunk is not followed by an empty trailer");
+        return (payload, sizes)
+      }
+      payload.extend_from_slice(&body[..size]);
+      assert_eq!(&body[size..size + 2], b"\r\n", "chunk data is not followed by a line break");
+      body = &body[size + 2..];
+      sizes.push(size);
+    }
+  }
+
+  #[test]
+  fn streams_body_of_unknown_length_in_chunks() {
+    let parts = vec![b"Hello, ".to_vec(), b"world".to_vec(), vec![b'x'; 40_000], Vec::new(), b"!".to_vec()];
+    let expected = parts.concat();
+    let mut written = Vec::new();
+    streamed(parts).write_to(&mut written, true).unwrap();
+
+    let (head, body) = split_head(&written);
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(!head.contains("Content-Length"));
+    let (payload, sizes) = dechunk(body);
+    assert_eq!(payload, expected);
+    // Small writes are collected into one chunk, large ones are passed on as they are
+    assert_eq!(sizes, [12, 40_000, 1]);
+  }
+
+  #[test]
+  fn sends_empty_body_of_unknown_length_as_last_chunk_only() {
+    let mut written = Vec::new();
+    streamed(Vec::new()).write_to(&mut written, true).unwrap();
+    assert_eq!(split_head(&written).1, b"0\r\n\r\n");
+  }
+
+  #[test]
+  fn sends_body_of_unknown_length_unframed_without_chunking() {
+    let mut written = Vec::new();
+    streamed(vec![b"abc".to_vec(), b"def".to_vec()]).write_to(&mut written, false).unwrap();
+    let (head, body) = split_head(&written);
+    assert!(!head.contains("Transfer-Encoding") && !head.contains("Content-Length"));
+    assert_eq!(body, b"abcdef");
+  }
+
+  #[test]
+  fn does_not_chunk_body_of_known_length() {
+    let mut written = Vec::new();
+    Response::ok(b"abc".to_vec()).write_to(&mut written, true).unwrap();
+    let (head, body) = split_head(&written);
+    assert!(head.contains("Content-Length: 3\r\n") && !head.contains("Transfer-Encoding"));
+    assert_eq!(body, b"abc");
+  }
+}
@@ -0,0 +1,325 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use super::assets;
+use super::config::Config;
+use super::error::ServerError;
+use super::escape;
+
+/// Templates compiled into the binary. A theme directory can override each of them with a file of the same name.
+const EMBEDDED: &[(&str, &str)] = &[
+  ("files.html"  , include_str!("../../templates/files.html")),
+  ("header.html" , include_str!("../../templates/header.html")),
+  ("entries.html", include_str!("../../templates/entries.html")),
+  ("toolbar.html", include_str!("../../templates/toolbar.html")),
+  ("trash.html"  , include_str!("../../templates/trash.html"))
+];
+
+/// Deep enough for sensible layouts, shallow enough to stop a template that includes itself.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+pub type Context = HashMap<&'static str, Value>;
+
+pub enum Value {
+  Text(String),
+  Bool(bool),
+  List(Vec<Context>)
+}
+
+impl Value {
+  fn is_truthy(&self) -> bool {
+    match self {
+      Value::Text(text)  => !text.is_empty(),
+      Value::Bool(value) => *value,
+      Value::List(items) => !items.is_empty()
+    }
+  }
+}
+
+impl From<String> for Value {
+  fn from(text: String) -> Self { Value::Text(text) }
+}
+
+impl From<&str> for Value {
+  fn from(text: &str) -> Self { Value::Text(text.to_string()) }
+}
+
+impl From<Cow<'_, str>> for Value {
+  fn from(text: Cow<'_, str>) -> Self { Value::Text(text.into_owned()) }
+}
+
+impl From<bool> for Value {
+  fn from(value: bool) -> Self { Value::Bool(value) }
+}
+
+impl From<Vec<Context>> for Value {
+  fn from(items: Vec<Context>) -> Self { Value::List(items) }
+}
+
+#[derive(Clone, Copy)]
+enum Escape { Html, Js, Raw }
+
+enum Node {
+  Text(String),
+  Variable { name: String, escape: Escape },
+  Each     { name: String, body: Vec<Node> },
+  If       { name: String, then: Vec<Node>, otherwise: Vec<Node> },
+  Include(String),
+  Asset(String)
+}
+
+/// Renders the template `name` with `context`.
+///
+/// Supported syntax:
+/// - `{{name}}` HTML-escaped variable, `{{name|js}}` for a JS string inside an attribute, `{{{name}}}` unescaped
+/// - `{{#each list}}...{{/each}}` repeats the body for every item; names resolve in the item first, then outwards
+/// - `{{#if name}}...{{else}}...{{/if}}` tests for a non-empty text or list, or a true flag (missing names are false)
+/// - `{{> other.html}}` includes another template with the current context
+/// - `{{asset icons/favicon.ico}}` is the content-hashed URL of a static asset
+///
+/// Templates are looked up in the theme directory first, then among the embedded ones.
+pub fn render(config: &Config, name: &str, context: &Context) -> Result<String, ServerError> {
+  let mut out = String::new();
+  render_into(&mut out, config, name, &mut vec![context], 0)?;
+  Ok(out)
+}
+
+fn render_into(out: &mut String, config: &Config, name: &str, scopes: &mut Vec<&Context>, depth: usize) -> Result<(), ServerError> {
+  if depth > MAX_INCLUDE_DEPTH {
+    return Err(ServerError::Internal(format!("Template {name}: includes nested deeper than {MAX_INCLUDE_DEPTH}")))
+  }
+  let nodes = parse(&source(config.theme.as_deref(), name)?).map_err(|e| ServerError::Internal(format!("Template {name}: {e}")))?;
+  render_nodes(out, config, name, &nodes, scopes, depth)
+}
+
+fn render_nodes<'a>(out: &mut String, config: &Config, template: &str, nodes: &[Node], scopes: &mut Vec<&'a Context>, depth: usize) -> Result<(), ServerError> {
+  let lookup = |scopes: &Vec<&'a Context>, name: &str| scopes.iter().rev().find_map(|scope| scope.get(name));
+
+  for node in nodes {
+    match node {
+      Node::Text(text)                   => out.push_str(text),
+      Node::Variable { name, escape }    => match lookup(scopes, name) {
+        Some(Value::Text(text)) => out.push_str(&match escape {
+          Escape::Html => escape::html(text),
+          Escape::Js   => escape::html(&escape::js(text)),
+          Escape::Raw  => text.clone()
+        }),
+        Some(_) => return Err(ServerError::Internal(format!("Template {template}: `{name}` is not text"))),
+        None    => return Err(ServerError::Internal(format!("Template {template}: unknown variable `{name}`")))
+      },
+      Node::Each { name, body }          => match lookup(scopes, name) {
+        Some(Value::List(items)) => for item in items {
+          scopes.push(item);
+          let rendered = render_nodes(out, config, template, body, scopes, depth);
+          scopes.pop();
+          rendered?;
+        },
+        Some(_) => return Err(ServerError::Internal(format!("Template {template}: `{name}` is not a list"))),
+        None    => ()
+      },
+      Node::If { name, then, otherwise } => {
+        let branch = if lookup(scopes, name).is_some_and(Value::is_truthy) { then } else { otherwise };
+        render_nodes(out, config, template, branch, scopes, depth)?
+      },
+      Node::Include(other)               => render_into(out, config, other, scopes, depth + 1)?,
+      Node::Asset(asset)                 => out.push_str(&escape::html(&assets::url(config, asset)?))
+    }
+  }
+  Ok(())
+}
+
+/// The theme's version of `name` if there is one, the embedded one otherwise.
+fn source(theme: Option<&Path>, name: &str) -> Result<String, ServerError> {
+  if let Some(theme) = theme {
+    match fs::read_to_string(theme.join(name)) {
+      Ok(source)                                => return Ok(source),
+      Err(e) if e.kind() == ErrorKind::NotFound => (),
+      Err(e)                                    => return Err(ServerError::Internal(format!("Template {} could not be read: {e}", theme.join(name).display())))
+    }
+  }
+  EMBEDDED.iter()
+    .find(|(embedded, _)| *embedded == name)
+    .map(|(_, source)| source.to_string())
+    .ok_or_else(|| ServerError::Internal(format!("Template {name} does not exist")))
+}
+
+fn parse(source: &str) -> Result<Vec<Node>, String> {
+  let mut rest = source;
+  let (nodes, end) = parse_block(&mut rest)?;
+  match end {
+    None      => Ok(nodes),
+    Some(tag) => Err(format!("unexpected `{{{{{tag}}}}}`"))
+  }
+}
+
+/// Parses nodes up to the next `{{else}}`, `{{/each}}` or `{{/if}}` (returned as the second value) or the end of input.
+fn parse_block(rest: &mut &str) -> Result<(Vec<Node>, Option<String>), String> {
+  let mut nodes = Vec::new();
+
+  while let Some(start) = rest.find("{{") {
+    if start > 0 { nodes.push(Node::Text(rest[..start].to_string())) }
+    let raw = rest[start..].starts_with("{{{");
+    let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+    let tag_start = start + open.len();
+    let length = rest[tag_start..].find(close).ok_or_else(|| format!("unclosed `{open}`"))?;
+    let tag = rest[tag_start..tag_start + length].trim().to_string();
+    *rest = &rest[tag_start + length + close.len()..];
+
+    if raw {
+      nodes.push(Node::Variable { name: tag, escape: Escape::Raw });
+    } else if let Some(name) = tag.strip_prefix("#each ") {
+      let (body, end) = parse_block(rest)?;
+      if end.as_deref() != Some("/each") { return Err(format!("`{{{{#each {name}}}}}` is not closed by `{{{{/each}}}}`")) }
+      nodes.push(Node::Each { name: name.trim().to_string(), body });
+    } else if let Some(name) = tag.strip_prefix("#if ") {
+      let (then, mut end) = parse_block(rest)?;
+      let otherwise = if end.as_deref() == Some("else") {
+        let (otherwise, after) = parse_block(rest)?;
+        end = after;
+        otherwise
+      } else { Vec::new() };
+      if end.as_deref() != Some("/if") { return Err(format!("`{{{{#if {name}}}}}` is not closed by `{{{{/if}}}}`")) }
+      nodes.push(Node::If { name: name.trim().to_string(), then, otherwise });
+    } else if let Some(asset) = tag.strip_prefix("asset ") {
+      nodes.push(Node::Asset(asset.trim().to_string()));
+    } else if let Some(name) = tag.strip_prefix('>') {
+      nodes.push(Node::Include(name.trim().to_string()));
+    } else if matches!(tag.as_str(), "else" | "/each" | "/if") {
+      return Ok((nodes, Some(tag)))
+    } else {
+      let (name, escape) = match tag.split_once('|') {
+        None                                          => (tag.as_str(), Escape::Html),
+        Some((name, filter)) if filter.trim() == "js" => (name, Escape::Js),
+        Some((_, filter))                             => return Err(format!("unknown filter `{}`", filter.trim()))
+      };
+      nodes.push(Node::Variable { name: name.trim().to_string(), escape });
+    }
+  }
+
+  if !rest.is_empty() { nodes.push(Node::Text(rest.to_string())) }
+  *rest = "";
+  Ok((nodes, None))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  /// A theme directory holding `templates`, removed when dropped.
+  struct Theme { directory: PathBuf, config: Config }
+
+  impl Theme {
+    fn new(test: &str, templates: &[(&str, &str)]) -> Self {
+      let directory = std::env::temp_dir().join(format!("fileserve-template-{}-{test}", std::process::id()));
+      fs::create_dir_all(&directory).unwrap();
+      for (name, source) in templates { fs::write(directory.join(name), source).unwrap() }
+      let config = Config { theme: Some(directory.clone()), ..Config::default() };
+      Self { directory, config }
+    }
+  }
+
+  impl Drop for Theme {
+    fn drop(&mut self) { let _ = fs::remove_dir_all(&self.directory); }
+  }
+
+  fn item(name: &str) -> Context {
+    Context::from([("name", name.into())])
+  }
+
+  fn context() -> Context {
+    Context::from([
+      ("title", "<b>\"Files\" & 'more'</b>".into()),
+      ("path" , "a'b\"c</script>\\".into()),
+      ("empty", "".into()),
+      ("yes"  , true.into()),
+      ("no"   , false.into()),
+      ("items", vec![item("x"), item("y")].into()),
+      ("none" , Vec::new().into())
+    ])
+  }
+
+  fn render_source(source: &str) -> Result<String, ServerError> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let theme = Theme::new(&COUNT.fetch_add(1, Ordering::Relaxed).to_string(), &[("page.html", source)]);
+    render(&theme.config, "page.html", &context())
+  }
+
+  fn failure(source: &str) -> String {
+    match render_source(source) {
+      Err(ServerError::Internal(message)) => message,
+      Err(e)                              => panic!("unexpected error {e}"),
+      Ok(out)                             => panic!("{source} rendered as {out}")
+    }
+  }
+
+  #[test]
+  fn escapes_variables() {
+    assert_eq!(render_source("<h1>{{title}}</h1>").unwrap(), "<h1>&lt;b&gt;&quot;Files&quot; &amp; &#39;more&#39;&lt;/b&gt;</h1>");
+    assert_eq!(render_source("{{{ title }}}").unwrap(), "<b>\"Files\" & 'more'</b>");
+    let js = render_source("<a onclick=\"go('{{ path | js }}')\">").unwrap();
+    assert_eq!(js, format!("<a onclick=\"go('{}')\">", escape::html(&escape::js("a'b\"c</script>\\"))));
+    assert_eq!(js, "<a onclick=\"go('a\\x27b\\x22c\\x3c\\x2fscript\\x3e\\\\')\">");
+  }
+
+  #[test]
+  fn renders_conditions_and_lists() {
+    assert_eq!(render_source("{{#if yes}}Y{{else}}N{{/if}}{{#if no}}Y{{else}}N{{/if}}{{#if empty}}Y{{/if}}{{#if missing}}Y{{/if}}").unwrap(), "YN");
+    assert_eq!(render_source("{{#if items}}has{{/if}}{{#if none}}none{{else}}no items{{/if}}").unwrap(), "hasno items");
+    assert_eq!(render_source("{{#each items}}[{{name}} of {{title}}]{{/each}}{{#each none}}x{{/each}}{{#each missing}}x{{/each}}").unwrap(),
+      format!("[x of {0}][y of {0}]", escape::html("<b>\"Files\" & 'more'</b>")));
+    assert_eq!(render_source("{{#each items}}{{#if yes}}{{#each items}}{{name}}{{/each}}{{else}}-{{/if}}{{/each}}").unwrap(), "xyxy");
+    assert_eq!(render_source("{{#if yes}}{{#if no}}a{{else}}b{{/if}}{{else}}c{{/if}}").unwrap(), "b");
+  }
+
+  #[test]
+  fn rejects_malformed_templates() {
+    for (source, message) in [
+      ("{{title"                                    , "unclosed `{{`"),
+      ("{{{title}}"                                 , "unclosed `{{{`"),
+      ("{{#if yes}}a"                               , "is not closed by `{{/if}}`"),
+      ("{{#each items}}a{{/if}}"                    , "is not closed by `{{/each}}`"),
+      ("{{#if yes}}a{{else}}b{{else}}c{{/if}}"      , "is not closed by `{{/if}}`"),
+      ("{{#if yes}}{{#each items}}{{else}}{{/each}}{{/if}}", "is not closed by `{{/each}}`"),
+      ("a{{else}}b"                                 , "unexpected `{{else}}`"),
+      ("{{/each}}"                                  , "unexpected `{{/each}}`"),
+      ("{{title|url}}"                              , "unknown filter `url`")
+    ] {
+      assert!(failure(source).ends_with(message), "{source}: {}", failure(source));
+    }
+  }
+
+  #[test]
+  fn rejects_unknown_and_mistyped_variables() {
+    assert!(failure("{{missing}}").contains("unknown variable `missing`"));
+    assert!(failure("{{items}}").contains("`items` is not text"));
+    assert!(failure("{{#each title}}{{/each}}").contains("`title` is not a list"));
+  }
+
+  #[test]
+  fn includes_templates_up_to_a_depth() {
+    let theme = Theme::new("include", &[
+      ("page.html" , "<{{> part.html}}>"),
+      ("part.html" , "{{#each items}}{{> name.html}}{{/each}}"),
+      ("name.html" , "{{name}}"),
+      ("loop.html" , "{{> loop.html}}"),
+      ("lost.html" , "{{> nowhere.html}}")
+    ]);
+    assert_eq!(render(&theme.config, "page.html", &context()).unwrap(), "<xy>");
+    assert!(matches!(render(&theme.config, "loop.html", &context()), Err(ServerError::Internal(message)) if message.contains("nested deeper")));
+    assert!(matches!(render(&theme.config, "lost.html", &context()), Err(ServerError::Internal(message)) if message.contains("does not exist")));
+  }
+
+  #[test]
+  fn falls_back_to_embedded_templates() {
+    let theme = Theme::new("embedded", &[]);
+    assert_eq!(source(theme.config.theme.as_deref(), "files.html").unwrap(), include_str!("../../templates/files.html"));
+    for (name, source) in EMBEDDED {
+      assert!(parse(source).is_ok(), "{name}");
+    }
+  }
+}
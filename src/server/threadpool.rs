@@ -1,4 +1,4 @@
-use std::{ sync::{ mpsc, Arc, Mutex }, thread, time::Duration };
+use std::{ panic::{ self, AssertUnwindSafe }, sync::{ mpsc, Arc, Mutex }, thread, time::Duration };
 
 pub type ThreadPoolError<'a> = &'a str;
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -45,7 +45,7 @@ impl Drop for ThreadPool {
       println!("Shut down worker {id}");
 
       if let Some(thread) = worker.thread.take() {
-        thread.join().expect(format!("Shutdown Worker (id: {id}) Error: Join failed").as_str())
+        thread.join().unwrap_or_else(|_| panic!("Shutdown Worker (id: {id}) Error: Join failed"))
       }
     }
   }
@@ -64,13 +64,18 @@ impl Worker {
 
 fn work(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> ! {
   loop {
-    match receiver.lock() {
-      Ok(exclusive_message) =>
-        match exclusive_message.recv() {
-          Ok(job) => { println!("HTTP request delegated to worker {id}"); job() },
-          Err(e)  => { thread::sleep(Duration::from_secs(1)); println!("Running Worker Error: Receiving message failed. {e}") }
-        },
-      Err(e) => { thread::sleep(Duration::from_secs(1)); println!("Running Worker Error: Receiver lock failed. {e}") }
+    // The lock is only held while waiting for a job, so the other workers can take the next ones while this one runs
+    let message = match receiver.lock() {
+      Ok(exclusive_message) => exclusive_message.recv(),
+      Err(e)                => { thread::sleep(Duration::from_secs(1)); println!("Running Worker Error: Receiver lock failed. {e}"); continue }
+    };
+    match message {
+      Ok(job) => {
+        println!("HTTP request delegated to worker {id}");
+        // A panicking job must not take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() { println!("Running Worker Error: Job of worker {id} panicked") }
+      },
+      Err(e)  => { thread::sleep(Duration::from_secs(1)); println!("Running Worker Error: Receiving message failed. {e}") }
     }
   }
-}
\ No newline at end of file
+}
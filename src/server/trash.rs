@@ -0,0 +1,203 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::config::Config;
+use super::error::ServerError;
+use super::escape;
+use super::path;
+use super::response::{Response, Status};
+use super::template::{self, Context};
+use super::{date, listing, unique, HTTPRequestType, Request};
+
+/// The hidden directory below the root holding deleted entries. `path::resolve` refuses to enter it.
+pub const TRASH_DIR: &str = ".trash";
+/// Where the recycle bin is served. Nothing else is reachable below it.
+pub const PREFIX   : &str = "/.trash/";
+
+/// Every item is a directory `<id>` holding the deleted `entry` and an `info` file with its origin.
+const ENTRY: &str = "entry";
+const INFO : &str = "info";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How often expired items are purged in the background.
+pub const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub struct Item {
+  pub id      : String,
+  /// Relative to the root.
+  pub original: PathBuf,
+  pub deleted : SystemTime,
+  pub is_dir  : bool,
+  pub size    : u64
+}
+
+impl Item {
+  fn read(directory: &Path) -> Option<Self> {
+    let info = fs::read_to_string(directory.join(INFO)).ok()?;
+    let value = |key: &str| info.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix('='));
+    let metadata = fs::symlink_metadata(directory.join(ENTRY)).ok()?;
+    Some(Self {
+      id      : directory.file_name()?.to_str()?.to_string(),
+      original: PathBuf::from(path::decode_url(value("path")?)),
+      deleted : UNIX_EPOCH.checked_add(Duration::from_secs(value("deleted")?.parse().ok()?))?,
+      is_dir  : metadata.is_dir(),
+      size    : metadata.len()
+    })
+  }
+
+  /// When the item is purged. A retention too long to represent keeps it, like a retention of 0.
+  fn expires(&self, config: &Config) -> Option<SystemTime> {
+    match config.trash_retention {
+      0    => None,
+      days => self.deleted.checked_add(Duration::from_secs(days.checked_mul(SECONDS_PER_DAY)?))
+    }
+  }
+
+  fn name(&self) -> String {
+    self.original.file_name().unwrap_or_default().to_string_lossy().to_string()
+  }
+
+  fn to_json(&self, config: &Config) -> String {
+    let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    format!("{{\"id\":\"{}\",\"path\":\"/{}\",\"kind\":\"{}\",\"size\":{},\"deleted\":{},\"expires\":{}}}",
+      escape::json(&self.id),
+      escape::json(&self.original.to_string_lossy()),
+      if self.is_dir { "directory" } else { "file" },
+      self.size,
+      secs(self.deleted),
+      self.expires(config).map(|e| secs(e).to_string()).unwrap_or("null".to_string()))
+  }
+}
+
+fn trash_dir(config: &Config) -> PathBuf {
+  config.root.join(TRASH_DIR)
+}
+
+/// Whether `path` is the recycle bin or inside it, where only this module may make changes.
+pub fn contains(config: &Config, path: &Path) -> bool {
+  path.starts_with(trash_dir(config))
+}
+
+/// The items in the recycle bin, most recently deleted first.
+fn items(config: &Config) -> Result<Vec<Item>, ServerError> {
+  let mut items = match fs::read_dir(trash_dir(config)) {
+    Ok(entries)                               => entries.filter_map(|entry| Item::read(&entry.ok()?.path())).collect::<Vec<Item>>(),
+    Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+    Err(e)                                    => return Err(e.into())
+  };
+  items.sort_by(|a, b| b.deleted.cmp(&a.deleted).then(a.id.cmp(&b.id)));
+  Ok(items)
+}
+
+fn item(config: &Config, id: &str) -> Result<Item, ServerError> {
+  path::file_name(id)?;
+  Item::read(&trash_dir(config).join(id)).ok_or_else(|| ServerError::NotFound(format!("{id} is not in the trash")))
+}
+
+/// Moves `target` (an entry inside the root) into the recycle bin.
+pub fn put(config: &Config, target: &Path) -> Result<(), ServerError> {
+  let relative = target.strip_prefix(&config.root).map_err(|_| ServerError::Forbidden(format!("{} is outside the root", target.display())))?;
+  let now = SystemTime::now();
+  let id = unique::id(now);
+  let deleted = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+  let directory = trash_dir(config).join(&id);
+  fs::create_dir_all(&directory)?;
+  fs::write(directory.join(INFO), format!("path={}\ndeleted={}\n", path::encode_url(relative.as_os_str()), deleted))?;
+  if let Err(e) = fs::rename(target, directory.join(ENTRY)) {
+    let _ = fs::remove_dir_all(&directory);
+    return Err(e.into())
+  }
+  println!("Moved {} to the trash as {id}", target.display());
+  Ok(())
+}
+
+/// Moves an item back to where it was deleted from, recreating missing parent directories.
+fn restore(config: &Config, id: &str) -> Result<Response, ServerError> {
+  let item = item(config, id)?;
+  let target = path::resolve(&config.root, &item.original, config.symlinks)?;
+  if fs::symlink_metadata(&target).is_ok() {
+    return Err(ServerError::Conflict(format!("/{} already exists", item.original.display())))
+  }
+  if let Some(parent) = target.parent() { fs::create_dir_all(parent)? }
+
+  let directory = trash_dir(config).join(id);
+  fs::rename(directory.join(ENTRY), &target)?;
+  fs::remove_dir_all(&directory)?;
+  println!("Restored {} from the trash", target.display());
+  Ok(Response::text(Status::Ok, &format!("/{} restored", item.original.display())))
+}
+
+fn purge(config: &Config, id: &str) -> Result<Response, ServerError> {
+  let item = item(config, id)?;
+  fs::remove_dir_all(trash_dir(config).join(id))?;
+  println!("Purged {} from the trash", item.original.display());
+  Ok(Response::text(Status::Ok, &format!("/{} purged", item.original.display())))
+}
+
+fn purge_all(config: &Config) -> Result<Response, ServerError> {
+  let items = items(config)?;
+  for item in &items { fs::remove_dir_all(trash_dir(config).join(&item.id))? }
+  Ok(Response::text(Status::Ok, &format!("{} items purged", items.len())))
+}
+
+/// Purges the items older than the retention period.
+pub fn expire(config: &Config) {
+  let now = SystemTime::now();
+  match items(config) {
+    Ok(items) => for item in items.iter().filter(|item| item.expires(config).is_some_and(|expires| expires <= now)) {
+      match fs::remove_dir_all(trash_dir(config).join(&item.id)) {
+        Ok(()) => println!("Expired {} from the trash", item.original.display()),
+        Err(e) => println!("Trash Error: {e}")
+      }
+    },
+    Err(e)    => println!("Trash Error: {e}")
+  }
+}
+
+fn list(config: &Config, header: &Request) -> Result<Response, ServerError> {
+  expire(config);
+  let items = items(config)?;
+  if header.wants_json() {
+    let items = items.iter().map(|item| item.to_json(config)).collect::<Vec<String>>();
+    return Ok(Response::ok(format!("{{\"items\":[{}]}}", items.join(",")).into_bytes())
+      .with_header("Content-Type", "application/json")
+      .with_header("Cache-Control", "no-store"))
+  }
+
+  let context = Context::from([
+    ("items", items.iter().map(|item| Context::from([
+      ("id"          , item.id.clone().into()),
+      ("name"        , item.name().into()),
+      ("original"    , format!("/{}", item.original.to_string_lossy()).into()),
+      ("is_directory", item.is_dir.into()),
+      ("size"        , if item.is_dir { String::new() } else { listing::human_size(item.size) }.into()),
+      ("deleted"     , date::format_short(item.deleted).into()),
+      ("expires"     , item.expires(config).map(date::format_short).unwrap_or("never".to_string()).into())
+    ])).collect::<Vec<Context>>().into())
+  ]);
+  Ok(Response::ok(template::render(config, "trash.html", &context)?.into_bytes())
+    .with_header("Content-Type", "text/html; charset=utf-8")
+    .with_header("Cache-Control", "no-store"))
+}
+
+/// Serves the recycle bin: `GET /.trash/` lists it, `POST /.trash/<id>` with `Action: restore` or `Action: purge`
+/// and `DELETE /.trash/<id>` act on an item, and `DELETE /.trash/` empties it.
+pub fn handle(config: &Config, header: &Request) -> Result<Response, ServerError> {
+  if !config.trash { return Err(ServerError::NotFound("The trash is disabled".to_string())) }
+  let id = header.path().get(PREFIX.len()..).unwrap_or_default().trim_end_matches('/');
+
+  match (&header.r_type, id, header.header("Action").map(String::as_str)) {
+    (HTTPRequestType::GET, "", _)                => list(config, header),
+    (HTTPRequestType::GET, id, _)                => Err(ServerError::NotFound(format!("{id} can't be downloaded from the trash, restore it first"))),
+    (HTTPRequestType::DELETE, "", _)             => purge_all(config),
+    (HTTPRequestType::DELETE, id, _)
+    | (HTTPRequestType::POST, id, Some("purge")) => purge(config, id),
+    (HTTPRequestType::POST, id, Some("restore")) => restore(config, id),
+    (HTTPRequestType::POST, _, action)           => Err(ServerError::BadRequest(format!("Invalid trash Action `{}`", action.unwrap_or_default()))),
+    (method, _, _)                               => Err(ServerError::MethodNotAllowed(format!("Method {method} is not supported in the trash")))
+  }
+}
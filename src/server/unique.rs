@@ -0,0 +1,11 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A name for something created at `time` that is unique within this process, e.g. `784111777-000000042-7`.
+/// Names of the same second sort by creation.
+pub fn id(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  format!("{}-{:09}-{}", since_epoch.as_secs(), since_epoch.subsec_nanos(), COUNTER.fetch_add(1, Ordering::Relaxed))
+}
@@ -0,0 +1,437 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::cache;
+use super::config::Config;
+use super::error::ServerError;
+use super::escape;
+use super::listing::Listing;
+use super::path;
+use super::response::{Response, Status};
+use super::trash;
+use super::unique;
+use super::Request;
+
+/// Gives up on finding a free `name (n).ext` after this many tries.
+const MAX_RENAMES: usize = 1000;
+
+/// Uploads are received into hidden files named like this, next to their target. Names starting with it are
+/// reserved, left out of listings, and removed at startup.
+const TEMP_PREFIX: &str = ".upload-";
+
+/// How much a file may grow before the free disk space is checked again.
+const FREE_SPACE_INTERVAL: u64 = 4 << 20;
+
+/// What an upload does when a file of the same name already exists.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConflictPolicy { Overwrite, Reject, Rename }
+
+impl TryFrom<&str> for ConflictPolicy {
+  type Error = ServerError;
+
+  fn try_from(value: &str) -> Result<Self, Self::Error> {
+    match value {
+      "overwrite" => Ok(ConflictPolicy::Overwrite),
+      "reject"    => Ok(ConflictPolicy::Reject),
+      "rename"    => Ok(ConflictPolicy::Rename),
+      _           => Err(ServerError::ConfigError(format!("Invalid conflict policy `{value}` (expected overwrite, reject or rename)")))
+    }
+  }
+}
+
+impl ConflictPolicy {
+  /// The policy asked for by a `Conflict` header or `?conflict=` query, the configured one otherwise.
+  pub fn for_request(config: &Config, header: &Request) -> Result<Self, ServerError> {
+    Ok(Self::requested(header)?.unwrap_or(config.upload_conflict))
+  }
+
+  /// The policy given by a `Conflict` header or `?conflict=` query, if any.
+  fn requested(header: &Request) -> Result<Option<Self>, ServerError> {
+    header.header("Conflict").cloned().or_else(|| header.query("conflict"))
+      .map(|value| Self::try_from(value.to_ascii_lowercase().as_str()).map_err(|e| ServerError::BadRequest(e.to_string())))
+      .transpose()
+  }
+}
+
+/// A file received in an upload: the name the client sent and the one it was stored under.
+pub struct Stored {
+  pub name  : String,
+  pub stored: String
+}
+
+impl Stored {
+  pub fn to_json(&self) -> String {
+    format!("{{\"name\":\"{}\",\"stored\":\"{}\"}}", escape::json(&self.name), escape::json(&self.stored))
+  }
+}
+
+/// `name (n).ext`, or `name` itself for `n == 0`. Dotfiles keep their leading dot.
+fn numbered(name: &str, n: usize) -> String {
+  match name.rfind('.').filter(|dot| *dot > 0) {
+    _ if n == 0 => name.to_string(),
+    Some(dot)   => format!("{} ({n}){}", &name[..dot], &name[dot..]),
+    None        => format!("{name} ({n})")
+  }
+}
+
+fn is_temporary(name: &OsStr) -> bool {
+  name.as_bytes().starts_with(TEMP_PREFIX.as_bytes())
+}
+
+/// Hides the temporary files of uploads in progress from a directory listing.
+pub fn hide_temporary(listing: &mut Listing) {
+  listing.entries.retain(|entry| !is_temporary(&entry.name))
+}
+
+/// An uploaded file being received. It is written to a hidden temporary file next to its target and only appears
+/// under its name once `commit` is called; if it is dropped before that, the temporary file is removed.
+pub struct Pending {
+  file         : File,
+  temporary    : Option<PathBuf>,
+  directory    : PathBuf,
+  /// The relative path of the directory below the upload's target for folder uploads, with a trailing `/`.
+  parents      : String,
+  name         : String,
+  policy       : ConflictPolicy,
+  written      : u64,
+  max_size     : u64,
+  min_free     : u64,
+  /// The free disk space was last found sufficient for the file to grow up to this size.
+  checked_until: u64
+}
+
+/// Names of temporary upload files can't be given to anything else, or `clean_up` would remove it.
+pub fn check_reserved(name: &OsStr) -> Result<(), ServerError> {
+  match is_temporary(name) {
+    true  => Err(ServerError::BadRequest(format!("The name {} is reserved", name.to_string_lossy()))),
+    false => Ok(())
+  }
+}
+
+/// Creates the directories of `parents`, a `/`-separated relative path, below `directory` where they are missing.
+/// Every component has to be a plain name, and the names of new directories have to pass the name policy; all of
+/// them are checked before anything is created.
+fn create_parents(config: &Config, directory: &Path, parents: Option<&str>) -> Result<PathBuf, ServerError> {
+  let mut current = directory.to_path_buf();
+  let mut missing = Vec::new();
+  for component in parents.map(|parents| parents.split('/')).into_iter().flatten() {
+    current.push(path::file_name(component)?);
+    check_reserved(OsStr::new(component))?;
+    let target = path::resolve(&config.root, &current, config.symlinks)?;
+    match fs::metadata(&target) {
+      Ok(metadata) if metadata.is_dir()         => (),
+      Ok(_)                                     => return Err(ServerError::Conflict(format!("{component} is not a directory"))),
+      Err(e) if e.kind() == ErrorKind::NotFound => {
+        path::check_name(OsStr::new(component), config.name_policy)?;
+        missing.push(target);
+      },
+      Err(e)                                    => return Err(e.into())
+    }
+  }
+
+  for target in missing {
+    match fs::create_dir(&target) {
+      Ok(())                                         => println!("Created directory {}", target.display()),
+      // By another upload of the same folder meanwhile
+      Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+      Err(e)                                         => return Err(e.into())
+    }
+  }
+  Ok(current)
+}
+
+/// Starts receiving the file `name` in `directory` (a path relative to the root). A `name` holding a relative path,
+/// as sent for folder uploads, puts the file into subdirectories, which are created as needed. Clashes are resolved
+/// according to `policy` when the file is committed, but an upload that is going to be rejected fails right away.
+/// An existing symlink of that name is replaced like a file; the upload is not written through it.
+pub fn begin(config: &Config, header: &Request, directory: &Path, name: &str, policy: ConflictPolicy) -> Result<Pending, ServerError> {
+  let (parents, name) = match name.rsplit_once('/') {
+    Some((parents, name)) => (Some(parents), name),
+    None                  => (None, name)
+  };
+  let directory = create_parents(config, directory, parents)?;
+  let target = path::resolve_entry(&config.root, &directory.join(path::file_name(name)?), config.symlinks)?;
+  check_reserved(OsStr::new(name))?;
+  match policy {
+    ConflictPolicy::Overwrite                                   => cache::check_write(header, &target, config.etag)?,
+    ConflictPolicy::Reject if target.symlink_metadata().is_ok() => return Err(ServerError::Conflict(format!("{name} already exists"))),
+    ConflictPolicy::Reject | ConflictPolicy::Rename             => ()
+  }
+
+  let directory = target.parent().map(Path::to_path_buf).unwrap_or_else(|| config.root.clone());
+  let temporary = directory.join(format!("{TEMP_PREFIX}{}", unique::id(SystemTime::now())));
+  let file = File::options().write(true).create_new(true).open(&temporary)?;
+  Ok(Pending {
+    file,
+    temporary    : Some(temporary),
+    directory,
+    parents      : parents.map(|parents| format!("{parents}/")).unwrap_or_default(),
+    name         : name.to_string(),
+    policy,
+    written      : 0,
+    max_size     : config.max_file_size,
+    min_free     : config.min_free_space,
+    checked_until: 0
+  })
+}
+
+impl Pending {
+  /// Flushes the file to disk and moves it to its final name. Returns the name it was stored under.
+  pub fn commit(&mut self) -> Result<Stored, ServerError> {
+    let Some(temporary) = self.temporary.clone() else {
+      return Err(ServerError::Internal(format!("Upload of {} was already committed", self.name)))
+    };
+    self.file.sync_all()?;
+
+    let stored = match self.policy {
+      // rename replaces an existing file atomically, so readers see either the old or the new content
+      ConflictPolicy::Overwrite => { fs::rename(&temporary, self.directory.join(&self.name))?; self.name.clone() },
+      ConflictPolicy::Reject    => match place(&temporary, &self.directory.join(&self.name)) {
+        Ok(())                                         => self.name.clone(),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(ServerError::Conflict(format!("{} already exists", self.name))),
+        Err(e)                                         => return Err(not_stored(&self.name, e))
+      },
+      ConflictPolicy::Rename    => self.link_free_name(&temporary)?
+    };
+    self.temporary = None;
+    // Best effort: makes the new name itself durable
+    let _ = File::open(&self.directory).and_then(|directory| directory.sync_all());
+
+    println!("Stored upload {}", self.directory.join(&stored).display());
+    Ok(Stored { name: format!("{}{}", self.parents, self.name), stored: format!("{}{stored}", self.parents) })
+  }
+
+  fn link_free_name(&self, temporary: &Path) -> Result<String, ServerError> {
+    for n in 0..MAX_RENAMES {
+      let candidate = numbered(&self.name, n);
+      match place(temporary, &self.directory.join(&candidate)) {
+        Ok(())                                         => return Ok(candidate),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+        Err(e)                                         => return Err(not_stored(&candidate, e))
+      }
+    }
+    Err(ServerError::Conflict(format!("No free name found for {}", self.name)))
+  }
+}
+
+/// Moves `temporary` to `target`, failing with `AlreadyExists` instead of replacing anything, so a file created
+/// meanwhile is never clobbered. That's what a hard link does; filesystems without them (FAT, many network shares)
+/// get the name claimed by an empty file first, which the rename then replaces.
+fn place(temporary: &Path, target: &Path) -> io::Result<()> {
+  match fs::hard_link(temporary, target) {
+    Ok(())                                         => return fs::remove_file(temporary),
+    Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(e),
+    Err(_)                                         => ()
+  }
+  File::options().write(true).create_new(true).open(target)?;
+  fs::rename(temporary, target).inspect_err(|_| { let _ = fs::remove_file(target); })
+}
+
+fn not_stored(name: &str, e: io::Error) -> ServerError {
+  ServerError::Internal(format!("{name} could not be stored under its own name: {e}"))
+}
+
+impl Pending {
+  /// Fails before the file grows beyond the size limit, or would leave less than the minimum free disk space.
+  fn reserve(&mut self, additional: u64) -> io::Result<()> {
+    let size = self.written + additional;
+    if self.max_size > 0 && size > self.max_size {
+      return Err(io::Error::new(ErrorKind::FileTooLarge, format!("{} is larger than the limit of {} bytes", self.name, self.max_size)))
+    }
+    if size > self.checked_until {
+      let needed = additional.max(FREE_SPACE_INTERVAL);
+      check_free_space(&self.directory, needed, self.min_free)?;
+      self.checked_until = self.written + needed;
+    }
+    Ok(())
+  }
+}
+
+impl Write for Pending {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.reserve(buf.len() as u64)?;
+    let written = self.file.write(buf)?;
+    self.written += written as u64;
+    Ok(written)
+  }
+  fn flush(&mut self) -> io::Result<()> { self.file.flush() }
+}
+
+impl Drop for Pending {
+  fn drop(&mut self) {
+    if let Some(temporary) = self.temporary.take() {
+      match fs::remove_file(&temporary) {
+        Ok(()) => println!("Discarded incomplete upload of {}", self.directory.join(&self.name).display()),
+        Err(e) => println!("Upload Error: {} could not be removed: {e}", temporary.display())
+      }
+    }
+  }
+}
+
+/// Stores the body of a `PUT` request as the file at the request path. As `PUT` replaces what is there, an existing
+/// file is overwritten unless another conflict policy is asked for; `If-None-Match: *` or `If-Match` guard against
+/// replacing changes. Answers `201 Created` for a new file and `200 OK` for a replaced one.
+pub fn put<R: Read>(stream: &mut R, config: &Config, header: &Request) -> Result<Response, ServerError> {
+  let url_path = path::url_path(&header.url)?;
+  let (Some(directory), Some(name)) = (url_path.parent(), url_path.file_name().filter(|_| !header.path().ends_with('/'))) else {
+    return Err(ServerError::BadRequest("PUT needs a file name".to_string()))
+  };
+  let name = name.to_str().ok_or_else(|| ServerError::BadRequest(format!("Invalid file name {}", name.to_string_lossy())))?;
+
+  let parent = path::resolve(&config.root, directory, config.symlinks)?;
+  if !parent.is_dir() { return Err(ServerError::Conflict(format!("The parent of {name} does not exist"))) }
+  let target = parent.join(name);
+  if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_dir()) { return Err(ServerError::Conflict(format!("{name} is a directory"))) }
+
+  let length = header.header("Content-Length").map(|value| value.parse::<u64>()).transpose()?.unwrap_or(0);
+  if config.max_file_size > 0 && length > config.max_file_size {
+    return Err(ServerError::PayloadTooLarge(format!("{name} is larger than the limit of {} bytes", config.max_file_size)))
+  }
+  check_request(config, header, directory)?;
+
+  let existed = target.symlink_metadata().is_ok();
+  let mut file = begin(config, header, directory, name, ConflictPolicy::requested(header)?.unwrap_or(ConflictPolicy::Overwrite))?;
+  let received = io::copy(stream, &mut file)?;
+  // A client hanging up early ends the body without an error
+  if received < length { return Err(ServerError::BadRequest(format!("Body ended after {received} of {length} bytes"))) }
+  let stored = file.commit()?;
+
+  let location = directory.strip_prefix("/").unwrap_or(directory).join(&stored.stored);
+  let (status, message) = match (existed, stored.stored == stored.name) {
+    (true, true) => (Status::Ok, format!("/{} replaced", location.display())),
+    _            => (Status::Created, format!("/{} created", location.display()))
+  };
+  let href = location.iter().map(path::encode_url).collect::<Vec<String>>().join("/");
+  Ok(Response::text(status, &message).with_header("Location", &format!("/{href}")))
+}
+
+/// Refuses an upload into `directory` (a path relative to the root) before its body is read, if the announced
+/// `Content-Length` exceeds the size limit or the free disk space.
+pub fn check_request(config: &Config, header: &Request, directory: &Path) -> Result<(), ServerError> {
+  let Some(length) = header.header("Content-Length").and_then(|value| value.parse::<u64>().ok()) else { return Ok(()) };
+  if config.max_upload_size > 0 && length > config.max_upload_size {
+    return Err(ServerError::PayloadTooLarge(format!("Upload of {length} bytes is larger than the limit of {} bytes", config.max_upload_size)))
+  }
+  check_free_space(&path::resolve(&config.root, directory, config.symlinks)?, length, config.min_free_space)?;
+  Ok(())
+}
+
+fn check_free_space(directory: &Path, needed: u64, min_free: u64) -> io::Result<()> {
+  match available_space(directory) {
+    Some(available) if available < needed.saturating_add(min_free) =>
+      Err(io::Error::new(ErrorKind::StorageFull, format!("Not enough free disk space for {needed} more bytes"))),
+    _ => Ok(())
+  }
+}
+
+/// The disk space available to unprivileged users on the filesystem holding `path`, if it can be determined.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn available_space(path: &Path) -> Option<u64> {
+  use std::ffi::CString;
+  use std::os::raw::{c_char, c_int, c_ulong};
+
+  /// `struct statvfs` of glibc and musl on 64-bit Linux
+  #[repr(C)]
+  #[derive(Default)]
+  #[allow(dead_code)]
+  struct StatVfs {
+    f_bsize  : c_ulong,
+    f_frsize : c_ulong,
+    f_blocks : u64,
+    f_bfree  : u64,
+    f_bavail : u64,
+    f_files  : u64,
+    f_ffree  : u64,
+    f_favail : u64,
+    f_fsid   : c_ulong,
+    f_flag   : c_ulong,
+    f_namemax: c_ulong,
+    f_spare  : [c_int; 6]
+  }
+  extern "C" { fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int; }
+
+  let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+  let mut stat = StatVfs::default();
+  // SAFETY: `path` is NUL-terminated and `stat` is a properly laid out, writable statvfs struct
+  match unsafe { statvfs(path.as_ptr(), &mut stat) } {
+    0 => Some(stat.f_bavail.saturating_mul(stat.f_frsize)),
+    _ => None
+  }
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn available_space(_path: &Path) -> Option<u64> {
+  None
+}
+
+/// Removes the temporary files left behind by uploads interrupted when the server stopped. The trash is skipped,
+/// as are symlinked directories.
+pub fn clean_up(config: &Config) {
+  fn clean(directory: &Path, skip: &Path, removed: &mut usize) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+      let (entry_path, file_type) = entry.and_then(|entry| Ok((entry.path(), entry.file_type()?)))?;
+      if file_type.is_dir() && entry_path != skip {
+        if let Err(e) = clean(&entry_path, skip, removed) { println!("Upload Error: {}: {e}", entry_path.display()) }
+      } else if file_type.is_file() && is_temporary(entry_path.file_name().unwrap_or_default()) {
+        fs::remove_file(&entry_path)?;
+        *removed += 1;
+      }
+    }
+    Ok(())
+  }
+
+  let mut removed = 0;
+  match clean(&config.root, &config.root.join(trash::TRASH_DIR), &mut removed) {
+    Ok(()) if removed > 0 => println!("Removed {removed} incomplete uploads"),
+    Ok(())                => (),
+    Err(e)                => println!("Upload Error: {e}")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::MetadataExt;
+
+  fn scratch(base: &Path, test: &str) -> PathBuf {
+    let directory = base.join(format!("fileserve-upload-{}-{test}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory
+  }
+
+  #[test]
+  fn places_without_replacing() {
+    let directory = scratch(&std::env::temp_dir(), "place");
+    let (temporary, target) = (directory.join(".upload-1"), directory.join("file"));
+    fs::write(&temporary, b"new").unwrap();
+    place(&temporary, &target).unwrap();
+    assert_eq!(fs::read(&target).unwrap(), b"new");
+    assert!(!temporary.exists());
+
+    fs::write(&temporary, b"newer").unwrap();
+    assert_eq!(place(&temporary, &target).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(&target).unwrap(), b"new");
+    assert_eq!(fs::read(&temporary).unwrap(), b"newer");
+    fs::remove_dir_all(&directory).unwrap();
+  }
+
+  #[test]
+  fn leaves_no_placeholder_when_nothing_works() {
+    // Neither a hard link nor a rename works across filesystems
+    if !Path::new("/dev/shm").is_dir() { return }
+    let (here, there) = (scratch(&std::env::temp_dir(), "cross"), scratch(Path::new("/dev/shm"), "cross"));
+    if fs::metadata(&here).unwrap().dev() != fs::metadata(&there).unwrap().dev() {
+      let (temporary, target) = (here.join(".upload-1"), there.join("file"));
+      fs::write(&temporary, b"new").unwrap();
+      assert!(place(&temporary, &target).is_err());
+      assert!(!target.exists());
+      assert_eq!(fs::read(&temporary).unwrap(), b"new");
+    }
+    fs::remove_dir_all(&here).unwrap();
+    fs::remove_dir_all(&there).unwrap();
+  }
+}